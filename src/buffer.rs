@@ -1,21 +1,74 @@
-use crate::mixer::Sample;
+use crate::{
+    constants::{BIT_DEPTH, CHANNELS},
+    mixer::Sample,
+};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::io::Cursor;
 
 #[derive(Default)]
 pub struct PlaybackBuffer {
     position: usize,
     buffer: Vec<Sample>,
+
+    /// Number of samples dropped from the front of the buffer since the
+    /// last [Self::clear], i.e. the sample index of `buffer[0]`
+    offset: usize,
+
     eof: bool,
     paused: bool,
+
+    /// Discard all buffered samples on the next call to [Self::next_sample]
+    discard_remaining: bool,
 }
 
 impl PlaybackBuffer {
     pub fn clear(&mut self) {
-        self.position = 0;
+        self.reset_position();
         self.buffer.clear();
+        self.offset = 0;
         self.eof = false;
     }
 
+    /// Moves playback to the given sample index counted from the last
+    /// [Self::clear]. Only samples that are still buffered can be seeked
+    /// to, returns false otherwise.
+    pub fn seek(&mut self, sample: usize) -> bool {
+        if sample < self.offset || sample >= self.offset + self.buffer.len() {
+            return false;
+        }
+
+        self.position = sample - self.offset;
+        true
+    }
+
+    /// Rewinds playback to the start of the buffered samples without
+    /// discarding them
+    pub fn reset_position(&mut self) {
+        self.position = 0;
+    }
+
+    /// Skips over up to `num_samples` samples that have not been played yet
+    pub fn advance(&mut self, num_samples: usize) {
+        self.position = self.buffer.len().min(self.position + num_samples);
+        if self.position >= self.buffer.len() {
+            self.position = 0;
+            self.offset += self.buffer.len();
+            self.buffer.clear();
+        }
+    }
+
+    /// Drops all buffered samples without emitting them
+    pub fn discard_remaining(&mut self) {
+        self.discard_remaining = true;
+    }
+
     pub fn next_sample(&mut self) -> Option<Sample> {
+        if self.discard_remaining {
+            self.clear();
+            self.discard_remaining = false;
+            return None;
+        }
+
         if self.paused {
             return Some((0, 0));
         }
@@ -24,11 +77,31 @@ impl PlaybackBuffer {
         self.position += 1;
         if self.position >= self.buffer.len() {
             self.position = 0;
+            self.offset += self.buffer.len();
             self.buffer.clear();
         }
         sample
     }
 
+    /// Number of buffered samples that have not been played yet
+    pub fn remaining(&self) -> usize {
+        self.buffer.len().saturating_sub(self.position)
+    }
+
+    /// Copies up to `max` of the samples that have not been played yet.
+    /// Returns nothing while paused, since nothing is being heard.
+    pub fn upcoming_samples(&self, max: usize) -> Vec<Sample> {
+        if self.paused {
+            return vec![];
+        }
+
+        let end = self.buffer.len().min(self.position + max);
+        self.buffer
+            .get(self.position..end)
+            .map(<[Sample]>::to_vec)
+            .unwrap_or_default()
+    }
+
     pub fn push_samples<I: IntoIterator<Item = Sample>>(&mut self, samples: I) {
         self.buffer.extend(samples);
     }
@@ -41,7 +114,81 @@ impl PlaybackBuffer {
         self.eof = eof;
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn set_paused(&mut self, paused: bool) {
         self.paused = paused;
     }
+
+    /// Encodes all buffered samples, including already played ones, as a
+    /// 16-bit stereo PCM WAV file
+    pub fn to_wav_bytes(&self, sample_rate: u32) -> Vec<u8> {
+        let spec = WavSpec {
+            channels: CHANNELS,
+            sample_rate,
+            bits_per_sample: BIT_DEPTH,
+            sample_format: SampleFormat::Int,
+        };
+
+        let mut cursor = Cursor::new(Vec::new());
+
+        // Writing to memory only fails if we run out of it
+        if let Ok(mut writer) = WavWriter::new(&mut cursor, spec) {
+            for (left, right) in &self.buffer {
+                writer.write_sample(*left).ok();
+                writer.write_sample(*right).ok();
+            }
+
+            writer.finalize().ok();
+        }
+
+        cursor.into_inner()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_wav_bytes_writes_header() {
+        let mut buffer = PlaybackBuffer::default();
+        buffer.push_samples([(1, -1), (2, -2), (3, -3)]);
+
+        let wav = buffer.to_wav_bytes(44100);
+
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[8..12], b"WAVE");
+        assert_eq!(&wav[12..16], b"fmt ");
+
+        let u16_at = |i: usize| u16::from_le_bytes([wav[i], wav[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([wav[i], wav[i + 1], wav[i + 2], wav[i + 3]]);
+
+        // PCM, stereo, 44.1 kHz, 16 bits per sample
+        assert_eq!(u16_at(20), 1);
+        assert_eq!(u16_at(22), CHANNELS);
+        assert_eq!(u32_at(24), 44100);
+        assert_eq!(u16_at(34), BIT_DEPTH);
+
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(40), 3 * 2 * 2);
+        assert_eq!(wav.len(), 44 + 3 * 2 * 2);
+        assert_eq!(u32_at(4) as usize, wav.len() - 8);
+    }
+
+    #[test]
+    fn advance_skips_unplayed_samples() {
+        let mut buffer = PlaybackBuffer::default();
+        buffer.push_samples([(1, 1), (2, 2), (3, 3)]);
+
+        buffer.advance(2);
+        assert_eq!(buffer.next_sample(), Some((3, 3)));
+
+        buffer.push_samples([(4, 4)]);
+        buffer.advance(10);
+        assert_eq!(buffer.remaining(), 0);
+        assert_eq!(buffer.next_sample(), None);
+    }
 }
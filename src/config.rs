@@ -1,6 +1,13 @@
+use crate::localization;
 use anyhow::Result;
+use chrono::NaiveTime;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
 use tokio::fs::read_to_string;
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -9,6 +16,47 @@ pub struct IrcConfig {
     pub server: String,
     pub channel: String,
     pub use_tls: Option<bool>,
+
+    /// Nicks that may use restricted commands
+    #[serde(default)]
+    pub admin_nicks: Vec<String>,
+
+    /// Keep the channel topic updated with playback and songleader status,
+    /// requires the bot to be allowed to set the topic
+    #[serde(default)]
+    pub irc_set_topic: bool,
+
+    /// Whether bot responses are sent as PRIVMSG or NOTICE
+    #[serde(default)]
+    pub irc_message_type: IrcMessageType,
+
+    /// Set the channel to moderated (+m) while singing, requires the bot to
+    /// be a channel operator
+    #[serde(default)]
+    pub irc_set_moderated_on_singing: bool,
+
+    /// Send responses meant for a single user as a NOTICE to them instead of
+    /// to the channel
+    #[serde(default)]
+    pub irc_use_notice_for_personal_responses: bool,
+
+    /// Nicks whose commands are always ignored. Nicks blocked at runtime are
+    /// persisted separately, and only those can be unblocked.
+    #[serde(default)]
+    pub irc_blocked_nicks: HashSet<String>,
+
+    /// Announce in the channel when a song starts buffering and when it
+    /// starts playing. Only logged otherwise.
+    #[serde(default)]
+    pub irc_announce_buffering: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IrcMessageType {
+    #[default]
+    Privmsg,
+    Notice,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -17,6 +65,341 @@ pub struct SongbookConfig {
 
     #[serde(with = "serde_regex")]
     pub songbook_re: Regex,
+
+    /// Songbook hosts that song requests may point to. When empty, any URL
+    /// matching `songbook_re` is accepted.
+    #[serde(default)]
+    pub allowed_songbook_domains: Vec<String>,
+
+    /// Maximum number of concurrent requests when validating song links
+    #[serde(default = "default_link_validation_concurrency")]
+    pub link_validation_concurrency: usize,
+
+    /// Maximum edit distance between normalized titles, relative to the
+    /// length of the longer title, for a request to be rejected as a
+    /// near-duplicate. Defaults to 0.15. Set to 0 to only reject identical
+    /// titles.
+    pub fuzzy_dedup_ratio: Option<f64>,
+
+    /// Maximum number of pending song requests per nick. Set to 0 to allow
+    /// any number of requests.
+    #[serde(default = "default_max_requests_per_user")]
+    pub max_requests_per_user: usize,
+}
+
+fn default_link_validation_concurrency() -> usize {
+    5
+}
+
+fn default_max_requests_per_user() -> usize {
+    3
+}
+
+impl SongbookConfig {
+    pub fn fuzzy_dedup_ratio(&self) -> f64 {
+        self.fuzzy_dedup_ratio.unwrap_or(0.15)
+    }
+
+    /// Per-nick request limit, or `None` if requests are unlimited
+    pub fn max_requests_per_user(&self) -> Option<usize> {
+        Some(self.max_requests_per_user).filter(|max| *max > 0)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct YoutubeConfig {
+    /// yt-dlp format selector passed as `--format`
+    #[serde(default = "default_youtube_audio_format")]
+    pub youtube_audio_format: String,
+
+    /// yt-dlp audio quality (0-9, 0 is best) passed as `--audio-quality`
+    #[serde(default = "default_youtube_audio_quality")]
+    pub youtube_audio_quality: u8,
+
+    /// Netscape format cookies file passed as `--cookies`, needed for age
+    /// restricted and region locked videos
+    pub ytdlp_cookies_file: Option<PathBuf>,
+
+    /// Browser to read cookies from, passed as `--cookies-from-browser`
+    pub ytdlp_cookies_from_browser: Option<String>,
+
+    /// Seconds after which a hanging yt-dlp process is killed
+    #[serde(default = "default_ytdlp_timeout_secs")]
+    pub ytdlp_timeout_secs: u64,
+
+    /// Seconds after which a hanging yt-dlp YouTube search is killed
+    #[serde(default = "default_search_yt_timeout_secs")]
+    pub search_yt_timeout_secs: u64,
+
+    /// SponsorBlock categories marked as chapters, passed as
+    /// `--sponsorblock-mark`
+    #[serde(default)]
+    pub ytdlp_sponsorblock_mark: Vec<String>,
+
+    /// SponsorBlock categories skipped during playback, passed as
+    /// `--sponsorblock-remove`
+    #[serde(default = "default_ytdlp_sponsorblock_remove")]
+    pub ytdlp_sponsorblock_remove: Vec<String>,
+}
+
+/// Prefers AAC in m4a, since symphonia can't decode the Opus audio that
+/// YouTube serves in webm
+fn default_youtube_audio_format() -> String {
+    "bestaudio[ext=m4a]/bestaudio".to_string()
+}
+
+fn default_youtube_audio_quality() -> u8 {
+    5
+}
+
+fn default_ytdlp_timeout_secs() -> u64 {
+    30
+}
+
+fn default_search_yt_timeout_secs() -> u64 {
+    10
+}
+
+fn default_ytdlp_sponsorblock_remove() -> Vec<String> {
+    vec!["sponsor".to_string()]
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TtsConfig {
+    /// Language used when normalizing text for TTS, e.g. "sv" or "en"
+    #[serde(default = "default_tts_language")]
+    pub tts_language: String,
+
+    /// Abbreviations that are expanded in song titles before TTS
+    #[serde(default = "localization::tts::default_abbreviations")]
+    pub tts_abbreviations: HashMap<String, String>,
+
+    /// Maximum length in characters of user submitted TTS text
+    #[serde(default = "default_tts_max_text_length")]
+    pub tts_max_text_length: usize,
+
+    /// Maximum length in characters of TTS text submitted by admins
+    #[serde(default = "default_tts_admin_max_text_length")]
+    pub tts_admin_max_text_length: usize,
+}
+
+fn default_tts_language() -> String {
+    "sv".to_string()
+}
+
+fn default_tts_max_text_length() -> usize {
+    500
+}
+
+fn default_tts_admin_max_text_length() -> usize {
+    2000
+}
+
+/// Overrides for the espeak voice, falling back to the defaults when unset
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct TtsVoiceConfig {
+    /// Name of the espeak voice, e.g. "Finnish"
+    pub voice: Option<String>,
+
+    /// Speaking rate in words per minute
+    pub rate: Option<u16>,
+
+    /// Base pitch, 0-100
+    pub pitch: Option<u8>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SongleaderConfig {
+    /// TTS voice overrides per songleader mode, keyed by mode name such as
+    /// "starting", "tempo", "bingo" or "singing"
+    #[serde(default)]
+    pub mode_voices: HashMap<String, TtsVoiceConfig>,
+
+    /// Number of nicks that need to type "!tempo" to start the next song
+    #[serde(default = "default_num_tempo_nicks")]
+    pub num_tempo_nicks: usize,
+
+    /// Number of nicks that need to type "!bingo" before singing starts
+    #[serde(default = "default_num_bingo_nicks")]
+    pub num_bingo_nicks: usize,
+
+    /// Seconds after entering tempo mode until the next song starts even if
+    /// not enough nicks have typed "!tempo"
+    #[serde(default = "default_tempo_deadline_secs")]
+    pub tempo_deadline_secs: u64,
+
+    /// Seconds that each "!tempo" takes off the tempo deadline
+    #[serde(default = "default_tempo_deadline_reduction_secs")]
+    pub tempo_deadline_reduction_secs: u64,
+
+    /// End the party automatically once all songs have been sung
+    #[serde(default)]
+    pub auto_end_on_empty: bool,
+
+    /// Seconds to keep music playing after entering bingo mode, giving the
+    /// music time to fade out under the next song announcement
+    #[serde(default = "default_bingo_music_fade_delay_secs")]
+    pub bingo_music_fade_delay_secs: u64,
+
+    /// Local time of day from which song requests are accepted, e.g.
+    /// "18:00:00". Requests are always open if unset.
+    pub requests_open_at: Option<NaiveTime>,
+
+    /// Local time of day after which song requests are no longer accepted.
+    /// May be earlier than `requests_open_at` for a window past midnight.
+    pub requests_close_at: Option<NaiveTime>,
+}
+
+fn default_num_tempo_nicks() -> usize {
+    3
+}
+
+fn default_num_bingo_nicks() -> usize {
+    3
+}
+
+fn default_tempo_deadline_secs() -> u64 {
+    420
+}
+
+fn default_tempo_deadline_reduction_secs() -> u64 {
+    60
+}
+
+fn default_bingo_music_fade_delay_secs() -> u64 {
+    2
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PlaybackConfig {
+    /// Maximum number of queued songs per platform, keyed by platform name
+    /// such as "irc" or "bot". Platforms without a limit are unlimited.
+    pub max_songs_per_platform: Option<HashMap<String, usize>>,
+
+    /// Number of distinct nicks that need to vote with !skip before the
+    /// current song is skipped
+    #[serde(default = "default_skip_votes_required")]
+    pub skip_votes_required: usize,
+
+    /// Milliseconds over which the end of a song is faded into the next
+    /// one, at most 10000. Set to 0 to switch songs immediately.
+    #[serde(default = "default_crossfade_duration_ms")]
+    pub crossfade_duration_ms: u64,
+}
+
+fn default_skip_votes_required() -> usize {
+    3
+}
+
+fn default_crossfade_duration_ms() -> u64 {
+    2000
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MixerConfig {
+    /// Volume of secondary channels (music) while ducked under speech,
+    /// between 0.0 and 1.0
+    #[serde(default = "default_duck_level")]
+    pub duck_level: f64,
+
+    /// Milliseconds over which secondary channels fade between the ducked
+    /// and normal volume
+    #[serde(default = "default_duck_fade_ms")]
+    pub duck_fade_ms: u64,
+}
+
+fn default_duck_level() -> f64 {
+    0.2
+}
+
+fn default_duck_fade_ms() -> u64 {
+    125
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct StateConfig {
+    /// Directory where state files are stored
+    #[serde(default = "default_state_dir")]
+    pub state_dir: PathBuf,
+
+    /// Overrides the path of the songleader state file
+    pub songleader_state_file: Option<PathBuf>,
+
+    /// Overrides the path of the playback state file
+    pub playback_state_file: Option<PathBuf>,
+
+    /// Directory that !music dump-buffer and !song export-state write files
+    /// to, and that !song import-state reads from
+    pub dump_dir: Option<PathBuf>,
+
+    /// Directory that !song bulk-request reads song lists from
+    pub import_dir: Option<PathBuf>,
+
+    /// Seconds between saving state files even when nothing has changed, in
+    /// case the bot doesn't get to save its state before crashing. Set to 0
+    /// to only save state on changes.
+    #[serde(default = "default_heartbeat_interval_secs")]
+    pub heartbeat_interval_secs: u64,
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_heartbeat_interval_secs() -> u64 {
+    300
+}
+
+impl StateConfig {
+    pub fn songleader_state_path(&self) -> PathBuf {
+        self.songleader_state_file
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("songleader_state.json"))
+    }
+
+    pub fn playback_state_path(&self) -> PathBuf {
+        self.playback_state_file
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("playback_state.json"))
+    }
+
+    pub fn blocked_nicks_path(&self) -> PathBuf {
+        self.state_dir.join("blocked_nicks.json")
+    }
+
+    pub fn dump_dir(&self) -> PathBuf {
+        self.dump_dir
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("dumps"))
+    }
+
+    /// Interval between state heartbeats, or `None` if they are disabled
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        Some(self.heartbeat_interval_secs)
+            .filter(|secs| *secs > 0)
+            .map(Duration::from_secs)
+    }
+
+    pub fn import_dir(&self) -> PathBuf {
+        self.import_dir
+            .clone()
+            .unwrap_or_else(|| self.state_dir.join("imports"))
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NetConfig {
+    /// Address to serve the mixed audio stream on
+    #[serde(default = "default_listen_addr")]
+    pub listen_addr: String,
+
+    /// Serve the audio stream over HTTP instead of raw TCP
+    #[serde(default)]
+    pub http_stream: bool,
+}
+
+fn default_listen_addr() -> String {
+    "0.0.0.0:7878".to_string()
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -26,6 +409,33 @@ pub struct Config {
 
     #[serde(flatten)]
     pub songbook: SongbookConfig,
+
+    #[serde(flatten)]
+    pub youtube: YoutubeConfig,
+
+    #[serde(flatten)]
+    pub tts: TtsConfig,
+
+    #[serde(flatten)]
+    pub songleader: SongleaderConfig,
+
+    #[serde(flatten)]
+    pub playback: PlaybackConfig,
+
+    #[serde(flatten)]
+    pub mixer: MixerConfig,
+
+    #[serde(flatten)]
+    pub net: NetConfig,
+
+    #[serde(flatten)]
+    pub state: StateConfig,
+}
+
+/// The example config, for tests that need a complete config
+#[cfg(test)]
+pub fn example() -> Config {
+    toml::from_str(include_str!("../Config.toml.example")).unwrap()
 }
 
 pub async fn load() -> Result<Config> {
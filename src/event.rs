@@ -5,20 +5,57 @@ use crate::{
     mixer::MixerAction,
     sources::{espeak::TextToSpeechAction, symphonia::SymphoniaAction},
 };
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast::error::{RecvError, TryRecvError};
 use tokio::sync::broadcast::{self, Receiver, Sender};
 
+const EVENT_BUS_CAPACITY: usize = 100;
+
 #[derive(Clone)]
 pub struct EventBus {
     tx: Sender<Event>,
+
+    /// Total number of events skipped by lagging subscribers
+    lagged_total: Arc<AtomicU64>,
+
+    /// Every sent event is also appended here if set, see [Self::new_capturing]
+    captured: Option<Arc<Mutex<Vec<Event>>>>,
 }
 
 impl EventBus {
     pub fn new() -> Self {
-        let (tx, _rx) = broadcast::channel::<Event>(100);
-        Self { tx }
+        let (tx, _rx) = broadcast::channel::<Event>(EVENT_BUS_CAPACITY);
+        Self {
+            tx,
+            lagged_total: Default::default(),
+            captured: None,
+        }
     }
+
+    /// Creates a bus that also records every sent event, so that tests can
+    /// inspect them without subscribing before the code under test runs
+    #[cfg(test)]
+    pub fn new_capturing() -> (Self, Arc<Mutex<Vec<Event>>>) {
+        let captured = Arc::new(Mutex::new(Vec::new()));
+        let bus = Self {
+            captured: Some(captured.clone()),
+            ..Self::new()
+        };
+
+        (bus, captured)
+    }
+
     pub fn send(&self, event: Event) {
+        if let Some(captured) = &self.captured {
+            captured.lock().unwrap().push(event.clone());
+
+            // Nobody needs to be subscribed to a capturing bus
+            if self.tx.receiver_count() == 0 {
+                return;
+            }
+        }
+
         let result = self.tx.send(event);
 
         if let Err(e) = result {
@@ -27,37 +64,73 @@ impl EventBus {
     }
 
     pub fn subscribe(&self) -> Subscriber {
-        Subscriber::new(self.tx.subscribe())
+        let subscriber = Subscriber::new(self.tx.subscribe(), self.lagged_total.clone());
+
+        let subscriber_count = self.subscriber_count();
+        let channel_capacity = self.channel_capacity();
+        if subscriber_count > channel_capacity / 2 {
+            warn!("EventBus has {subscriber_count} subscribers, which is close to its capacity of {channel_capacity}");
+        }
+
+        subscriber
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.tx.receiver_count()
+    }
+
+    pub fn channel_capacity(&self) -> usize {
+        EVENT_BUS_CAPACITY
+    }
+
+    /// Total number of events skipped by lagging subscribers, for metrics
+    pub fn lagged_total(&self) -> u64 {
+        self.lagged_total.load(Ordering::Relaxed)
     }
 }
 
 pub struct Subscriber {
     rx: Receiver<Event>,
+    lagged_total: Arc<AtomicU64>,
 }
 
 impl Subscriber {
-    pub fn new(rx: Receiver<Event>) -> Self {
-        Self { rx }
+    pub fn new(rx: Receiver<Event>, lagged_total: Arc<AtomicU64>) -> Self {
+        Self { rx, lagged_total }
     }
 
     pub fn try_recv(&mut self) -> Result<Event, TryRecvError> {
-        self.rx.try_recv()
+        let result = self.rx.try_recv();
+
+        if let Err(TryRecvError::Lagged(skipped)) = result {
+            self.lagged_total.fetch_add(skipped, Ordering::Relaxed);
+        }
+
+        result
     }
 
     pub async fn recv(&mut self) -> Event {
         loop {
-            let event = self.rx.recv().await;
-
-            match event {
-                Ok(event) => break event,
-                Err(RecvError::Closed) => {
-                    panic!("Tried to read recv from EventBus with all sender halves dropped, this should never happen")
-                }
-                Err(RecvError::Lagged(skipped)) => {
-                    warn!(
-                        "EventBus::Subscriber lagging behind senders, skipping {skipped} messages"
-                    );
-                }
+            if let Ok(event) = self.recv_or_lagged().await {
+                break event;
+            }
+        }
+    }
+
+    /// Like [Self::recv], but returns the number of skipped events when the
+    /// subscriber has lagged behind instead of skipping them silently
+    pub async fn recv_or_lagged(&mut self) -> Result<Event, u64> {
+        let event = self.rx.recv().await;
+
+        match event {
+            Ok(event) => Ok(event),
+            Err(RecvError::Closed) => {
+                panic!("Tried to read recv from EventBus with all sender halves dropped, this should never happen")
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                self.lagged_total.fetch_add(skipped, Ordering::Relaxed);
+                warn!("EventBus::Subscriber lagging behind senders, skipping {skipped} messages");
+                Err(skipped)
             }
         }
     }
@@ -83,3 +156,42 @@ pub fn debug(bus: &EventBus) {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capturing_bus_records_sent_events() {
+        let (bus, captured) = EventBus::new_capturing();
+
+        bus.send(Event::Mixer(MixerAction::UnduckSecondaryChannels));
+
+        assert!(matches!(
+            captured.lock().unwrap().as_slice(),
+            [Event::Mixer(MixerAction::UnduckSecondaryChannels)]
+        ));
+    }
+
+    #[test]
+    fn lagging_subscriber_increments_lagged_total() {
+        let bus = EventBus::new();
+        let mut subscriber = bus.subscribe();
+
+        // The channel may round its capacity up, so send plenty of events
+        for _ in 0..bus.channel_capacity() * 4 {
+            bus.send(Event::Mixer(MixerAction::UnduckSecondaryChannels));
+        }
+
+        let skipped = match subscriber.try_recv() {
+            Err(TryRecvError::Lagged(skipped)) => skipped,
+            other => panic!("expected subscriber to lag, got {other:?}"),
+        };
+        assert!(skipped > 0);
+        assert_eq!(bus.lagged_total(), skipped);
+
+        // The subscriber continues from the oldest retained event
+        assert!(subscriber.try_recv().is_ok());
+        assert_eq!(bus.lagged_total(), skipped);
+    }
+}
@@ -1,19 +1,306 @@
 use crate::{
+    config::IrcMessageType,
     event::{Event, EventBus},
+    metrics,
     mixer::MixerAction,
-    playback::{PlaybackAction, MAX_SONG_DURATION},
-    songbook::SongbookSong,
-    songleader::SongleaderAction,
-    sources::espeak::{Priority, TextToSpeechAction},
-    youtube::get_yt_song_info,
+    persist,
+    playback::{Platform, PlaybackAction, QueuePosition},
+    songbook::{self, SongbookSong},
+    songleader::{SongleaderAction, MAX_MODE_TIMEOUT_SECS},
+    sources::{
+        espeak::{truncate_text, Priority, TextToSpeechAction},
+        symphonia::{SymphoniaAction, MAX_CROSSFADE_DURATION_MS},
+    },
+    youtube,
 };
 use anyhow::Result;
 use futures::StreamExt;
 use irc::client::prelude::*;
+use std::{
+    collections::{HashSet, VecDeque},
+    num::NonZeroUsize,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{Mutex, Notify, RwLock},
+    time::{sleep, Instant},
+};
+
+/// Number of messages that can be sent in a burst
+const RATE_LIMIT_CAPACITY: u32 = 5;
+
+/// Time it takes to regain capacity for one message
+const RATE_LIMIT_REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum number of outgoing messages waiting to be sent
+const MESSAGE_QUEUE_CAPACITY: usize = 50;
+
+/// Maximum length in characters of song titles given to !song force-request
+const MAX_TITLE_LENGTH: usize = 200;
 
 #[derive(Clone, Debug)]
 pub enum IrcAction {
+    /// Sends a message using the configured `irc_message_type`
     SendMsg(String),
+
+    /// Sends a message as NOTICE regardless of `irc_message_type`
+    SendNotice(String),
+
+    /// Sends a response meant for a single user. Sent as a NOTICE to the
+    /// user if `irc_use_notice_for_personal_responses` is set, otherwise
+    /// like [IrcAction::SendMsg].
+    Reply { nick: String, msg: String },
+
+    /// Sets the channel topic
+    SetTopic { topic: String },
+
+    /// Playback status to show in the channel topic
+    PlaybackTopicInfo {
+        now_playing: Option<String>,
+        queue_len: usize,
+    },
+
+    /// Songleader status to show in the channel topic
+    SongleaderTopicInfo { mode: String },
+
+    /// Sets a channel mode, e.g. "+m"
+    SetChannelMode { mode: String },
+
+    /// Connected to the IRC server
+    Connected,
+
+    /// Lost connection to the IRC server
+    Disconnected,
+
+    /// Ignores all commands from a nick until unblocked
+    BlockNick { nick: String },
+
+    /// Stops ignoring commands from a nick
+    UnblockNick { nick: String },
+}
+
+/// Nicks blocked at runtime whose commands are ignored, shared between the
+/// incoming message loop and the bus event loop. Nicks blocked in the config
+/// are kept out of this set so that they are not persisted.
+type BlockedNicks = Arc<RwLock<HashSet<String>>>;
+
+/// Reads the nicks blocked at runtime, falling back to none
+async fn read_blocked_nicks(path: &Path) -> HashSet<String> {
+    match tokio::fs::read(path).await {
+        Ok(json) => serde_json::from_slice(&json).unwrap_or_default(),
+        Err(e) => {
+            info!("Error while reading blocked nicks: {:?}", e);
+            HashSet::new()
+        }
+    }
+}
+
+async fn persist_blocked_nicks(path: &Path, blocked_nicks: &HashSet<String>) {
+    let json = match serde_json::to_string_pretty(blocked_nicks) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("Error while serializing blocked nicks: {:?}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = persist::write_atomically(path, json).await {
+        error!("Error while writing blocked nicks: {:?}", e);
+    }
+}
+
+/// Status shown in the channel topic when `irc_set_topic` is enabled
+#[derive(Default)]
+struct TopicInfo {
+    now_playing: Option<String>,
+    queue_len: usize,
+    mode: Option<String>,
+}
+
+impl TopicInfo {
+    fn to_topic(&self) -> String {
+        let mut parts = vec![format!(
+            "🎵 Now Playing: {}",
+            self.now_playing.as_deref().unwrap_or("-")
+        )];
+
+        if let Some(mode) = &self.mode {
+            parts.push(format!("Mode: {mode}"));
+        }
+
+        parts.push(format!("Queue: {} songs", self.queue_len));
+
+        parts.join(" | ")
+    }
+}
+
+/// Worst case time it takes to send `num_messages` rate limited messages
+pub fn send_duration(num_messages: usize) -> Duration {
+    let throttled = num_messages.saturating_sub(RATE_LIMIT_CAPACITY as usize);
+    RATE_LIMIT_REFILL_INTERVAL * throttled as u32
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket rate limiter for outgoing IRC messages. Allows bursts of up
+/// to `capacity` messages while limiting the long-term rate to one message
+/// per `refill_interval`.
+pub struct IrcRateLimiter {
+    capacity: f64,
+    refill_interval: Duration,
+    bucket: Mutex<TokenBucket>,
+}
+
+impl IrcRateLimiter {
+    pub fn new(capacity: u32, refill_interval: Duration) -> Self {
+        Self {
+            capacity: capacity as f64,
+            refill_interval,
+            bucket: Mutex::new(TokenBucket {
+                tokens: capacity as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until a token is available and consumes it
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+
+                let now = Instant::now();
+                let refilled =
+                    (now - bucket.last_refill).as_secs_f64() / self.refill_interval.as_secs_f64();
+                bucket.tokens = (bucket.tokens + refilled).min(self.capacity);
+                bucket.last_refill = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+
+                self.refill_interval.mul_f64(1.0 - bucket.tokens)
+            };
+
+            sleep(wait).await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct PendingMessages {
+    commands: VecDeque<Command>,
+
+    /// Set when messages were dropped since the last sent message
+    truncated: bool,
+}
+
+impl PendingMessages {
+    /// Adds a command to the end of the queue. When the queue is full the
+    /// oldest chat message is dropped, other commands such as MODE or TOPIC
+    /// are never dropped.
+    fn push(&mut self, command: Command) {
+        if self.commands.len() >= MESSAGE_QUEUE_CAPACITY {
+            let droppable = self
+                .commands
+                .iter()
+                .position(|command| matches!(command, Command::PRIVMSG(..) | Command::NOTICE(..)));
+
+            if let Some(index) = droppable {
+                self.commands.remove(index);
+                self.truncated = true;
+            }
+        }
+
+        self.commands.push_back(command);
+    }
+
+    /// Takes the next command to send, sending a truncation notice first if
+    /// messages were dropped
+    fn pop(&mut self, irc_channel: &str) -> Option<Command> {
+        if self.truncated {
+            self.truncated = false;
+            return Some(Command::NOTICE(
+                irc_channel.to_string(),
+                "…(truncated)".to_string(),
+            ));
+        }
+
+        self.commands.pop_front()
+    }
+}
+
+/// Owns the IRC sender and sends queued commands at a rate that stays within
+/// the server's flood protection. When the queue is full the oldest chat
+/// message is dropped and a truncation notice is sent in its place.
+#[derive(Clone)]
+pub struct MessageQueue {
+    pending: Arc<Mutex<PendingMessages>>,
+    notify: Arc<Notify>,
+}
+
+impl MessageQueue {
+    pub fn new(irc_sender: Sender, irc_channel: String) -> Self {
+        let queue = Self {
+            pending: Default::default(),
+            notify: Default::default(),
+        };
+
+        {
+            let queue = queue.clone();
+
+            tokio::spawn(async move {
+                let rate_limiter =
+                    IrcRateLimiter::new(RATE_LIMIT_CAPACITY, RATE_LIMIT_REFILL_INTERVAL);
+
+                loop {
+                    let command = queue.pop(&irc_channel).await;
+
+                    rate_limiter.acquire().await;
+
+                    if let Err(e) = irc_sender.send(command) {
+                        error!("Error while sending IRC message: {:?}", e);
+                    }
+                }
+            });
+        }
+
+        queue
+    }
+
+    /// Adds a command to the end of the queue, see [PendingMessages::push]
+    pub async fn push(&self, command: Command) {
+        {
+            let mut pending = self.pending.lock().await;
+            pending.push(command);
+            metrics::IRC_MESSAGE_QUEUE_PENDING.set(pending.commands.len() as u64);
+        }
+
+        self.notify.notify_one();
+    }
+
+    /// Waits for the next command to send
+    async fn pop(&self, irc_channel: &str) -> Command {
+        loop {
+            {
+                let mut pending = self.pending.lock().await;
+                let command = pending.pop(irc_channel);
+                metrics::IRC_MESSAGE_QUEUE_PENDING.set(pending.commands.len() as u64);
+
+                if let Some(command) = command {
+                    return command;
+                }
+            }
+
+            self.notify.notified().await;
+        }
+    }
 }
 
 pub async fn init(bus: &EventBus, config: &crate::config::Config) -> Result<()> {
@@ -34,50 +321,151 @@ pub async fn init(bus: &EventBus, config: &crate::config::Config) -> Result<()>
 
     let mut stream = client.stream()?;
 
+    bus.send(Event::Irc(IrcAction::Connected));
+
+    let blocked_nicks_file = config.state.blocked_nicks_path();
+    let blocked_nicks = read_blocked_nicks(&blocked_nicks_file).await;
+    let blocked_nicks: BlockedNicks = Arc::new(RwLock::new(blocked_nicks));
+
     {
         let irc_channel = irc_channel.clone();
         let bus = bus.clone();
         let config = config.clone();
+        let blocked_nicks = blocked_nicks.clone();
 
         // Loop over incoming IRC messages
         tokio::spawn(async move {
             while let Ok(Some(message)) = stream.next().await.transpose() {
-                let target = message.response_target().map(|s| s.to_string());
+                if let Command::Response(Response::ERR_CHANOPRIVSNEEDED, args) = &message.command {
+                    warn!("Not allowed to change channel settings without operator privileges: {args:?}");
+                    continue;
+                }
+
+                if let Some(nick) = departed_nick(&message, &irc_channel) {
+                    bus.send(Event::Songleader(SongleaderAction::RemoveNick { nick }));
+                    continue;
+                }
+
                 let message = message.clone();
 
                 let irc_channel = irc_channel.clone();
                 let bus = bus.clone();
                 let config = config.clone();
+                let blocked_nicks = blocked_nicks.clone();
 
                 tokio::spawn(async move {
-                    let action = message_to_action(&message, &config).await;
+                    let action =
+                        incoming_message_to_action(&message, &irc_channel, &config, &blocked_nicks)
+                            .await;
 
-                    // Dispatch if msg resulted in action and msg is from target irc_channel
                     if let Some(action) = action {
-                        if target == Some(irc_channel) {
-                            bus.send(action);
-                        }
+                        bus.send(action);
                     }
                 });
             }
+
+            error!("IRC connection lost");
+            bus.send(Event::Irc(IrcAction::Disconnected));
         });
     }
 
     {
         // Loop over incoming bus messages
         let bus = bus.clone();
+        let set_topic = config.irc.irc_set_topic;
+        let message_type = config.irc.irc_message_type;
+        let personal_notices = config.irc.irc_use_notice_for_personal_responses;
+        let config_blocked_nicks = config.irc.irc_blocked_nicks.clone();
 
         tokio::spawn(async move {
-            let mut bus = bus.subscribe();
+            let mut bus_rx = bus.subscribe();
+            let message_queue = MessageQueue::new(irc_sender, irc_channel.clone());
+            let mut topic_info = TopicInfo::default();
+            let mut last_shown = None;
 
             loop {
-                let event = bus.recv().await;
+                let event = bus_rx.recv().await;
 
-                if let Event::Irc(IrcAction::SendMsg(msg)) = event {
-                    let result = irc_sender.send_privmsg(&irc_channel, &msg);
+                let action = match event {
+                    Event::Irc(action) => action,
+                    _ => continue,
+                };
 
-                    if let Err(e) = result {
-                        error!("Error while sending IRC message: {:?}", e);
+                match action {
+                    IrcAction::Reply { nick, msg } if personal_notices => {
+                        message_queue.push(Command::NOTICE(nick, msg)).await;
+                    }
+                    IrcAction::SendMsg(msg) | IrcAction::Reply { msg, .. } => {
+                        let command = match message_type {
+                            IrcMessageType::Privmsg => Command::PRIVMSG(irc_channel.clone(), msg),
+                            IrcMessageType::Notice => Command::NOTICE(irc_channel.clone(), msg),
+                        };
+
+                        message_queue.push(command).await;
+                    }
+                    IrcAction::SendNotice(msg) => {
+                        message_queue
+                            .push(Command::NOTICE(irc_channel.clone(), msg))
+                            .await;
+                    }
+                    IrcAction::SetTopic { topic } => {
+                        message_queue
+                            .push(Command::TOPIC(irc_channel.clone(), Some(topic)))
+                            .await;
+                    }
+                    IrcAction::SetChannelMode { mode } => {
+                        message_queue
+                            .push(Command::Raw(
+                                "MODE".to_string(),
+                                vec![irc_channel.clone(), mode],
+                            ))
+                            .await;
+                    }
+                    IrcAction::PlaybackTopicInfo {
+                        now_playing,
+                        queue_len,
+                    } => {
+                        topic_info.now_playing = now_playing;
+                        topic_info.queue_len = queue_len;
+                    }
+                    IrcAction::SongleaderTopicInfo { mode } => {
+                        topic_info.mode = Some(mode);
+                    }
+                    IrcAction::BlockNick { nick } => {
+                        let mut blocked_nicks = blocked_nicks.write().await;
+                        blocked_nicks.insert(nick.clone());
+                        persist_blocked_nicks(&blocked_nicks_file, &blocked_nicks).await;
+
+                        bus.send(Event::Irc(IrcAction::SendMsg(format!(
+                            "Ignoring commands from {nick}"
+                        ))));
+                    }
+                    IrcAction::UnblockNick { nick } => {
+                        let mut blocked_nicks = blocked_nicks.write().await;
+                        let msg = if blocked_nicks.remove(&nick) {
+                            persist_blocked_nicks(&blocked_nicks_file, &blocked_nicks).await;
+                            format!("No longer ignoring commands from {nick}")
+                        } else if config_blocked_nicks.contains(&nick) {
+                            format!("{nick} is blocked in the config and can't be unblocked")
+                        } else {
+                            format!("{nick} is not blocked")
+                        };
+
+                        bus.send(Event::Irc(IrcAction::SendMsg(msg)));
+                    }
+                    IrcAction::Connected | IrcAction::Disconnected => continue,
+                }
+
+                // Only touch the topic when the song or mode shown in it
+                // changes. The queue length changes with every request, so
+                // it is only refreshed along with them.
+                if set_topic {
+                    let shown = (topic_info.now_playing.clone(), topic_info.mode.clone());
+
+                    if last_shown.as_ref() != Some(&shown) {
+                        last_shown = Some(shown);
+                        let topic = topic_info.to_topic();
+                        bus.send(Event::Irc(IrcAction::SetTopic { topic }));
                     }
                 }
             }
@@ -87,6 +475,87 @@ pub async fn init(bus: &EventBus, config: &crate::config::Config) -> Result<()>
     Ok(())
 }
 
+/// Returns the nick of a user leaving the channel, if the message is about
+/// one leaving
+fn departed_nick(message: &Message, irc_channel: &str) -> Option<String> {
+    match &message.command {
+        Command::PART(channel, _) if channel == irc_channel => {
+            message.source_nickname().map(|nick| nick.to_string())
+        }
+        Command::QUIT(_) => message.source_nickname().map(|nick| nick.to_string()),
+        Command::KICK(channel, nick, _) if channel == irc_channel => Some(nick.clone()),
+        _ => None,
+    }
+}
+
+fn is_admin(nick: &str, config: &crate::config::Config) -> bool {
+    config.irc.admin_nicks.iter().any(|admin| admin == nick)
+}
+
+/// Turns an incoming message into an action, unless it is from a blocked
+/// nick or not sent to `irc_channel`
+async fn incoming_message_to_action(
+    message: &Message,
+    irc_channel: &str,
+    config: &crate::config::Config,
+    blocked_nicks: &BlockedNicks,
+) -> Option<Event> {
+    // Admins can't be blocked, so that they can't lock themselves out
+    if let Some(nick) = message.source_nickname() {
+        let blocked = config.irc.irc_blocked_nicks.contains(nick)
+            || blocked_nicks.read().await.contains(nick);
+
+        if blocked && !is_admin(nick, config) {
+            return None;
+        }
+    }
+
+    let action = message_to_action(message, config).await?;
+
+    (message.response_target() == Some(irc_channel)).then_some(action)
+}
+
+fn max_tts_text_length(nick: &str, config: &crate::config::Config) -> usize {
+    if is_admin(nick, config) {
+        config.tts.tts_admin_max_text_length
+    } else {
+        config.tts.tts_max_text_length
+    }
+}
+
+/// Parses the remaining words of a command as a tag. Tags may contain
+/// spaces and may be written as "tag:<tag>".
+fn parse_tag<'a>(words: impl Iterator<Item = &'a str>) -> Option<String> {
+    let tag = words.collect::<Vec<_>>().join(" ");
+    let tag = tag.strip_prefix("tag:").unwrap_or(&tag).trim();
+
+    Some(tag.to_string()).filter(|tag| !tag.is_empty())
+}
+
+/// Reads a JSON file containing a list of songbook songs
+async fn read_songs_file(path: &Path) -> Result<Vec<SongbookSong>> {
+    let json = tokio::fs::read(path).await?;
+    let songs = serde_json::from_slice(&json)?;
+
+    Ok(songs)
+}
+
+/// Enqueues the first YouTube search result for `search_terms`
+fn search_and_enqueue(search_terms: String, nick: String) -> Event {
+    if search_terms.is_empty() {
+        return Event::Irc(IrcAction::SendMsg(
+            "Error: Missing search terms! Usage: !ps <search terms>".to_string(),
+        ));
+    }
+
+    Event::Playback(PlaybackAction::EnqueueUrl {
+        url_or_search_terms: youtube::search_yt_query(&search_terms),
+        queued_by: nick,
+        platform: Platform::Irc,
+        position: QueuePosition::End,
+    })
+}
+
 async fn message_to_action(message: &Message, config: &crate::config::Config) -> Option<Event> {
     if let Command::PRIVMSG(_channel, text) = &message.command {
         let nick = message.source_nickname()?.to_string();
@@ -100,10 +569,14 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
         match cmd {
             "!play" | "!p" => {
                 let words: Vec<&str> = cmd_split.collect();
+
+                if words.first() == Some(&"search") {
+                    return Some(search_and_enqueue(words[1..].join(" "), nick));
+                }
+
                 let url_or_search_terms = words.join(" ");
 
-                let matches_songbook_url =
-                    config.songbook.songbook_re.is_match(&url_or_search_terms);
+                let matches_songbook_url = songbook::is_songbook_url(&url_or_search_terms, config);
 
                 if matches_songbook_url {
                     return Some(Event::Songleader(SongleaderAction::RequestSongUrl {
@@ -112,37 +585,89 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
                     }));
                 }
 
-                let song = get_yt_song_info(url_or_search_terms.to_string(), nick).await;
-
-                match song {
-                    Ok(song) if song.duration > MAX_SONG_DURATION.as_secs() => {
-                        Some(Event::Irc(IrcAction::SendMsg(format!(
-                            "Requested song is too long! Max duration is {} minutes.",
-                            MAX_SONG_DURATION.as_secs() / 60
-                        ))))
-                    }
-                    Ok(song) => Some(Event::Playback(PlaybackAction::Enqueue { song })),
-                    Err(e) => Some(Event::Irc(IrcAction::SendMsg(format!(
-                        "Error while getting song info: {e}"
-                    )))),
-                }
+                Some(Event::Playback(PlaybackAction::EnqueueUrl {
+                    url_or_search_terms,
+                    queued_by: nick,
+                    platform: Platform::Irc,
+                    position: QueuePosition::End,
+                }))
+            }
+            "!ps" => {
+                let words: Vec<&str> = cmd_split.collect();
+                Some(search_and_enqueue(words.join(" "), nick))
             }
-            "!queue" | "!q" | "!np" => {
-                let offset = cmd_split.next();
-                let offset = offset.and_then(|offset| offset.parse().ok());
+            "!np" => Some(Event::Playback(PlaybackAction::NowPlaying)),
+            "!queue" | "!q" => {
+                let offset_or_nick = cmd_split.next();
 
-                Some(Event::Playback(PlaybackAction::ListQueue { offset }))
+                match offset_or_nick {
+                    Some(offset_or_nick) => match offset_or_nick.parse::<usize>() {
+                        Ok(offset) => Some(Event::Playback(PlaybackAction::ListQueue {
+                            offset: Some(offset),
+                        })),
+                        Err(_) => Some(Event::Playback(PlaybackAction::ListQueueByNick {
+                            nick: offset_or_nick.to_string(),
+                        })),
+                    },
+                    None => Some(Event::Playback(PlaybackAction::ListQueue { offset: None })),
+                }
             }
             "!rm" => Some(Event::Playback(PlaybackAction::RmSongByNick { nick })),
+            "!skip" => Some(Event::Playback(PlaybackAction::SkipVote { nick })),
             "!speak" | "!say" => {
                 let words: Vec<&str> = cmd_split.collect();
-                let text = words.join(" ");
+                let text = truncate_text(&words.join(" "), max_tts_text_length(&nick, config));
 
                 Some(Event::TextToSpeech(TextToSpeechAction::Speak {
                     text,
                     prio: Priority::Low,
+                    voice: None,
+                }))
+            }
+            "!speak-interrupt" => {
+                if !is_admin(&nick, config) {
+                    return Some(Event::Irc(IrcAction::SendMsg(
+                        "Error: !speak-interrupt is restricted to admins".to_string(),
+                    )));
+                }
+
+                let words: Vec<&str> = cmd_split.collect();
+                let text = truncate_text(&words.join(" "), config.tts.tts_admin_max_text_length);
+
+                Some(Event::TextToSpeech(TextToSpeechAction::SpeakInterrupt {
+                    text,
                 }))
             }
+            "!announce" | "!announce-tts" => {
+                if !is_admin(&nick, config) {
+                    return Some(Event::Irc(IrcAction::SendMsg(format!(
+                        "Error: {cmd} is restricted to admins"
+                    ))));
+                }
+
+                let words: Vec<&str> = cmd_split.collect();
+                let text = words.join(" ");
+
+                if text.is_empty() {
+                    return Some(Event::Irc(IrcAction::SendMsg(format!(
+                        "Error: Usage: {cmd} <text>"
+                    ))));
+                }
+
+                Some(Event::Songleader(SongleaderAction::Announce {
+                    text,
+                    tts: cmd == "!announce-tts",
+                }))
+            }
+            "!tts-clear" => {
+                if !is_admin(&nick, config) {
+                    return Some(Event::Irc(IrcAction::SendMsg(
+                        "Error: !tts-clear is restricted to admins".to_string(),
+                    )));
+                }
+
+                Some(Event::TextToSpeech(TextToSpeechAction::ClearQueue))
+            }
             "!request" | "!req" | "!r" | "!add" => {
                 let words: Vec<&str> = cmd_split.collect();
                 let song = words.join(" ");
@@ -152,10 +677,35 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
                     queued_by: nick,
                 }))
             }
+            "!request-tag" => {
+                let Some(tag) = parse_tag(cmd_split) else {
+                    return Some(Event::Irc(IrcAction::SendMsg(
+                        "Error: Usage: !request-tag <tag>".to_string(),
+                    )));
+                };
+
+                Some(Event::Songleader(SongleaderAction::RequestSongByTag {
+                    tag,
+                    queued_by: nick,
+                }))
+            }
             "!tempo" | "tempo" => Some(Event::Songleader(SongleaderAction::Tempo { nick })),
             "!bingo" | "bingo" => Some(Event::Songleader(SongleaderAction::Bingo { nick })),
             "!skål" | "skål" => Some(Event::Songleader(SongleaderAction::Skål)),
-            "!ls" => Some(Event::Songleader(SongleaderAction::ListSongs)),
+            "!ls" => match parse_tag(cmd_split) {
+                // Listing by tag includes all songs, not just requests
+                Some(tag) => Some(Event::Songleader(SongleaderAction::ListSongs {
+                    tag: Some(tag),
+                })),
+                None => Some(Event::Songleader(SongleaderAction::ListRequests)),
+            },
+            "!ls-backup" => Some(Event::Songleader(SongleaderAction::ListBackup)),
+            "!ls-first" => Some(Event::Songleader(SongleaderAction::ListFirst)),
+            "!tempo-history" => {
+                let count = cmd_split.next().and_then(|count| count.parse().ok());
+
+                Some(Event::Songleader(SongleaderAction::TempoHistory { count }))
+            }
             "!help" => Some(Event::Songleader(SongleaderAction::Help)),
 
             // "Admin" commands for songleader
@@ -172,13 +722,23 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
                                 "Error: Missing song name! Usage: !song force-request <song name>"
                                     .to_string(),
                             )))
+                        } else if title.starts_with("http") {
+                            // A song created from a URL would never be found in the songbook
+                            Some(Event::Songleader(SongleaderAction::RequestSongUrl {
+                                url: title,
+                                queued_by: nick,
+                            }))
                         } else {
+                            // The title is also used as the id, keep it reasonably short
+                            let title: String = title.chars().take(MAX_TITLE_LENGTH).collect();
                             let song = SongbookSong {
                                 id: title.to_string(),
                                 url: None,
                                 title: Some(title.to_string()),
                                 book: None,
                                 queued_by: Some(nick),
+                                page_url: None,
+                                tags: vec![],
                             };
                             Some(Event::Songleader(SongleaderAction::RequestSong { song }))
                         }
@@ -191,7 +751,10 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
                     "pause" => Some(Event::Songleader(SongleaderAction::Pause)),
                     "end" | "finish" => Some(Event::Songleader(SongleaderAction::End)),
                     "begin" => Some(Event::Songleader(SongleaderAction::Begin)),
-                    "list" | "queue" => Some(Event::Songleader(SongleaderAction::ListSongs)),
+                    "list" | "queue" => {
+                        Some(Event::Songleader(SongleaderAction::ListSongs { tag: None }))
+                    }
+                    "validate-links" => Some(Event::Songleader(SongleaderAction::ValidateLinks)),
                     "rm" => {
                         let id: Vec<&str> = cmd_split.collect();
                         let id = id.join(" ");
@@ -204,6 +767,184 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
 
                         Some(Event::Songleader(SongleaderAction::RmSongById { id }))
                     }
+                    "swap" => {
+                        let (id_a, id_b) = match (cmd_split.next(), cmd_split.next()) {
+                            (Some(id_a), Some(id_b)) => (id_a.to_string(), id_b.to_string()),
+                            _ => {
+                                return Some(Event::Irc(IrcAction::SendMsg(
+                                    "Error: Missing song ids! Usage: !song swap <id_a> <id_b>"
+                                        .to_string(),
+                                )))
+                            }
+                        };
+
+                        Some(Event::Songleader(SongleaderAction::SwapRequests {
+                            id_a,
+                            id_b,
+                        }))
+                    }
+                    "reorder" => {
+                        let new_order: Vec<String> = cmd_split.map(|id| id.to_string()).collect();
+
+                        if new_order.is_empty() {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Missing song ids! Usage: !song reorder <id1> <id2> ..."
+                                    .to_string(),
+                            )));
+                        }
+
+                        Some(Event::Songleader(SongleaderAction::ReorderRequests {
+                            new_order,
+                        }))
+                    }
+                    "pin" | "unpin" => {
+                        let ids: Vec<String> = cmd_split.map(|id| id.to_string()).collect();
+
+                        if ids.is_empty() {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Missing song ids! Usage: !song {subcommand} <id1> <id2> ..."
+                            ))));
+                        }
+
+                        if subcommand == "pin" {
+                            Some(Event::Songleader(SongleaderAction::PinSongs { ids }))
+                        } else {
+                            Some(Event::Songleader(SongleaderAction::UnpinSongs { ids }))
+                        }
+                    }
+                    "bulk-request" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !song bulk-request is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        let file_name = cmd_split.next()?;
+
+                        let Some(path) =
+                            persist::file_in_dir(&config.state.import_dir(), file_name)
+                        else {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Invalid file name {file_name}"
+                            ))));
+                        };
+
+                        match read_songs_file(&path).await {
+                            Ok(songs) => {
+                                Some(Event::Songleader(SongleaderAction::BulkRequestSongs {
+                                    songs,
+                                }))
+                            }
+                            Err(e) => Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error while reading songs from {file_name}: {e}"
+                            )))),
+                        }
+                    }
+                    "toggle-requests" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !song toggle-requests is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        Some(Event::Songleader(SongleaderAction::ToggleRequestsOpen))
+                    }
+                    "set-timeout" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !song set-timeout is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        let mode = cmd_split.next();
+                        let seconds = cmd_split
+                            .next()
+                            .and_then(|secs| secs.parse().ok())
+                            .filter(|secs| *secs <= MAX_MODE_TIMEOUT_SECS);
+
+                        match (mode, seconds) {
+                            (Some(mode), Some(seconds)) => {
+                                Some(Event::Songleader(SongleaderAction::SetModeTimeout {
+                                    mode: mode.to_string(),
+                                    seconds,
+                                }))
+                            }
+                            _ => Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Usage: !song set-timeout <mode> <seconds>, at most {MAX_MODE_TIMEOUT_SECS}s"
+                            )))),
+                        }
+                    }
+                    "play" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !song play is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        let song_id: Vec<&str> = cmd_split.collect();
+                        let song_id = song_id.join(" ");
+
+                        if song_id.is_empty() {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Missing song id! Usage: !song play <id>".to_string(),
+                            )));
+                        }
+
+                        Some(Event::Songleader(SongleaderAction::PlaySongbookSong {
+                            song_id,
+                        }))
+                    }
+                    "export-state" | "import-state" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: !song {subcommand} is restricted to admins"
+                            ))));
+                        }
+
+                        let file_name = cmd_split.next().map(|file_name| file_name.to_string());
+
+                        if subcommand == "export-state" {
+                            return Some(Event::Songleader(SongleaderAction::ExportState {
+                                file_name,
+                            }));
+                        }
+
+                        let Some(file_name) = file_name else {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Usage: !song import-state <file name>".to_string(),
+                            )));
+                        };
+
+                        Some(Event::Songleader(SongleaderAction::ImportState {
+                            file_name,
+                        }))
+                    }
+                    "prioritize" | "deprioritize" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: !song {subcommand} is restricted to admins"
+                            ))));
+                        }
+
+                        let id: Vec<&str> = cmd_split.collect();
+                        let id = id.join(" ");
+
+                        if id.is_empty() {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Missing song id! Usage: !song {subcommand} <id>"
+                            ))));
+                        }
+
+                        if subcommand == "prioritize" {
+                            Some(Event::Songleader(SongleaderAction::PrioritizeSong { id }))
+                        } else {
+                            Some(Event::Songleader(SongleaderAction::DeprioritizeSong { id }))
+                        }
+                    }
+                    "promote-next" | "swap-with-next" => {
+                        Some(Event::Songleader(SongleaderAction::PromoteNextRequest))
+                    }
+                    "demote-first" => Some(Event::Songleader(SongleaderAction::DemoteFirstSong)),
                     _ => None,
                 }
             }
@@ -214,8 +955,97 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
 
                 match subcommand {
                     "next" | "skip" => Some(Event::Playback(PlaybackAction::Next)),
-                    "prev" => Some(Event::Playback(PlaybackAction::Prev)),
+                    "prev" => match cmd_split.next().map(str::parse::<NonZeroUsize>) {
+                        Some(Ok(n)) => Some(Event::Playback(PlaybackAction::PrevN { n })),
+                        Some(Err(_)) => Some(Event::Irc(IrcAction::SendMsg(
+                            "Error: Usage: !music prev [n], where n is at least 1".to_string(),
+                        ))),
+                        None => Some(Event::Playback(PlaybackAction::Prev)),
+                    },
+                    "jump" => {
+                        let index = cmd_split.next().and_then(|index| index.parse().ok())?;
+
+                        Some(Event::Playback(PlaybackAction::JumpToPlayed { index }))
+                    }
                     "play" | "resume" => Some(Event::Playback(PlaybackAction::Play)),
+                    "filter" | "filter-exclude" => {
+                        let Some(tag) = parse_tag(cmd_split) else {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Missing tag! Usage: !music {subcommand} <tag>"
+                            ))));
+                        };
+                        let tag = Some(tag);
+
+                        let (require_tag, exclude_tag) = if subcommand == "filter" {
+                            (tag, None)
+                        } else {
+                            (None, tag)
+                        };
+
+                        Some(Event::Playback(PlaybackAction::FilterQueue {
+                            require_tag,
+                            exclude_tag,
+                        }))
+                    }
+                    "clear-filter" => Some(Event::Playback(PlaybackAction::ClearFilter)),
+                    "shuffle" => Some(Event::Playback(PlaybackAction::Shuffle)),
+                    "crossfade" => {
+                        let Some(ms) = cmd_split
+                            .next()
+                            .and_then(|ms| ms.parse().ok())
+                            .filter(|ms| *ms <= MAX_CROSSFADE_DURATION_MS)
+                        else {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Usage: !music crossfade <ms>, at most {MAX_CROSSFADE_DURATION_MS} ms"
+                            ))));
+                        };
+
+                        Some(Event::Symphonia(SymphoniaAction::SetCrossfadeDuration {
+                            ms,
+                        }))
+                    }
+                    "seek" => {
+                        let Some(position_secs) =
+                            cmd_split.next().and_then(|secs| secs.parse().ok())
+                        else {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Usage: !music seek <seconds>".to_string(),
+                            )));
+                        };
+
+                        Some(Event::Playback(PlaybackAction::Seek { position_secs }))
+                    }
+                    "dump-buffer" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !music dump-buffer is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        let file_name = cmd_split.next().map(|file_name| file_name.to_string());
+
+                        Some(Event::Symphonia(SymphoniaAction::DumpBuffer { file_name }))
+                    }
+                    "block" | "unblock" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: !music {subcommand} is restricted to admins"
+                            ))));
+                        }
+
+                        let Some(target) = cmd_split.next() else {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Missing nick! Usage: !music {subcommand} <nick>"
+                            ))));
+                        };
+                        let nick = target.to_string();
+
+                        if subcommand == "block" {
+                            Some(Event::Irc(IrcAction::BlockNick { nick }))
+                        } else {
+                            Some(Event::Irc(IrcAction::UnblockNick { nick }))
+                        }
+                    }
                     "pause" => Some(Event::Playback(PlaybackAction::Pause)),
                     "rm" => {
                         let pos_or_nick = cmd_split.next();
@@ -236,6 +1066,101 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
                             None => Some(Event::Playback(PlaybackAction::RmSongByNick { nick })),
                         }
                     }
+                    "insert" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: !music insert is restricted to admins".to_string(),
+                            )));
+                        }
+
+                        let mut words: Vec<&str> = cmd_split.collect();
+                        let position = words.pop().and_then(|pos| pos.parse().ok());
+
+                        let (position, url_or_search_terms) = match position {
+                            Some(position) if !words.is_empty() => (position, words.join(" ")),
+                            _ => {
+                                return Some(Event::Irc(IrcAction::SendMsg(
+                                    "Error: Usage: !music insert <url> <position>".to_string(),
+                                )))
+                            }
+                        };
+
+                        Some(Event::Playback(PlaybackAction::EnqueueUrl {
+                            url_or_search_terms,
+                            queued_by: nick,
+                            platform: Platform::Irc,
+                            position: QueuePosition::At(position),
+                        }))
+                    }
+                    "insert-next" | "insert-before-last" => {
+                        if !is_admin(&nick, config) {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: !music {subcommand} is restricted to admins"
+                            ))));
+                        }
+
+                        let words: Vec<&str> = cmd_split.collect();
+                        let url_or_search_terms = words.join(" ");
+
+                        if url_or_search_terms.is_empty() {
+                            return Some(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Usage: !music {subcommand} <url>"
+                            ))));
+                        }
+
+                        let position = if subcommand == "insert-next" {
+                            QueuePosition::AfterCurrent
+                        } else {
+                            QueuePosition::BeforeLast
+                        };
+
+                        Some(Event::Playback(PlaybackAction::EnqueueUrl {
+                            url_or_search_terms,
+                            queued_by: nick,
+                            platform: Platform::Irc,
+                            position,
+                        }))
+                    }
+                    "upvote" | "downvote" => {
+                        let song_id = cmd_split.next()?.to_string();
+
+                        if subcommand == "upvote" {
+                            Some(Event::Playback(PlaybackAction::Upvote {
+                                song_id,
+                                user: nick,
+                            }))
+                        } else {
+                            Some(Event::Playback(PlaybackAction::Downvote {
+                                song_id,
+                                user: nick,
+                            }))
+                        }
+                    }
+                    "votes" => Some(Event::Playback(PlaybackAction::ListVotes)),
+                    "refresh-duration" => {
+                        let id = cmd_split.next()?.to_string();
+
+                        Some(Event::Playback(PlaybackAction::RefreshDuration { id }))
+                    }
+                    "trim" => {
+                        let id = cmd_split.next();
+                        let start_secs = cmd_split.next().and_then(|secs| secs.parse().ok());
+                        let end_secs = cmd_split.next().and_then(|secs| secs.parse().ok());
+
+                        match (id, start_secs, end_secs) {
+                            (Some(id), Some(start_secs), Some(end_secs)) => {
+                                Some(Event::Playback(PlaybackAction::Trim {
+                                    id: id.to_string(),
+                                    start_secs,
+                                    end_secs,
+                                }))
+                            }
+                            _ => Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Usage: !music trim <id> <start secs> <end secs>"
+                                    .to_string(),
+                            ))),
+                        }
+                    }
                     "volume" => {
                         let volume: f64 =
                             cmd_split.next().and_then(|volume| volume.parse().ok())?;
@@ -243,14 +1168,27 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
 
                         Some(Event::Mixer(MixerAction::SetSecondaryChannelVolume(volume)))
                     }
-                    "volume-ducked" => {
-                        let volume: f64 =
-                            cmd_split.next().and_then(|volume| volume.parse().ok())?;
+                    "duck-level" | "volume-ducked" => {
+                        let Some(volume) = cmd_split
+                            .next()
+                            .and_then(|volume| volume.parse::<f64>().ok())
+                        else {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Usage: !music duck-level <0.0-1.0>".to_string(),
+                            )));
+                        };
                         let volume = volume.clamp(0.0, 1.0);
 
-                        Some(Event::Mixer(MixerAction::SetSecondaryChannelDuckedVolume(
-                            volume,
-                        )))
+                        Some(Event::Mixer(MixerAction::SetDuckLevel(volume)))
+                    }
+                    "duck-fade" => {
+                        let Some(ms) = cmd_split.next().and_then(|ms| ms.parse().ok()) else {
+                            return Some(Event::Irc(IrcAction::SendMsg(
+                                "Error: Usage: !music duck-fade <ms>".to_string(),
+                            )));
+                        };
+
+                        Some(Event::Mixer(MixerAction::SetDuckFadeDuration(ms)))
                     }
                     "!queue" | "!q" => {
                         let offset = cmd_split.next();
@@ -268,3 +1206,162 @@ async fn message_to_action(message: &Message, config: &crate::config::Config) ->
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn privmsg(text: &str) -> Command {
+        Command::PRIVMSG("#channel".to_string(), text.to_string())
+    }
+
+    fn message_from(nick: &str, channel: &str, text: &str) -> Message {
+        Message {
+            tags: None,
+            prefix: Some(Prefix::Nickname(
+                nick.to_string(),
+                "user".to_string(),
+                "host".to_string(),
+            )),
+            command: Command::PRIVMSG(channel.to_string(), text.to_string()),
+        }
+    }
+
+    #[test]
+    fn search_enqueues_first_search_result() {
+        let event = search_and_enqueue("helan går".to_string(), "nick".to_string());
+
+        assert!(matches!(
+            event,
+            Event::Playback(PlaybackAction::EnqueueUrl {
+                url_or_search_terms,
+                queued_by,
+                platform: Platform::Irc,
+                position: QueuePosition::End,
+            }) if url_or_search_terms == "ytsearch1:helan går" && queued_by == "nick"
+        ));
+    }
+
+    #[test]
+    fn search_without_terms_is_an_error() {
+        let event = search_and_enqueue(String::new(), "nick".to_string());
+
+        assert!(matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg.starts_with("Error: Missing search terms")
+        ));
+    }
+
+    #[tokio::test]
+    async fn play_search_and_ps_search_youtube() {
+        let config = crate::config::example();
+
+        for text in ["!p search helan går", "!ps helan går"] {
+            let message = message_from("nick", &config.irc.channel, text);
+
+            assert!(matches!(
+                message_to_action(&message, &config).await,
+                Some(Event::Playback(PlaybackAction::EnqueueUrl {
+                    url_or_search_terms,
+                    ..
+                })) if url_or_search_terms == "ytsearch1:helan går"
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn blocked_nick_produces_no_events() {
+        let mut config = crate::config::example();
+        config.irc.admin_nicks = vec!["admin".to_string()];
+        config.irc.irc_blocked_nicks = HashSet::from(["spammer".to_string(), "admin".to_string()]);
+        let blocked_nicks: BlockedNicks =
+            Arc::new(RwLock::new(HashSet::from(["troll".to_string()])));
+
+        let action = |nick: &'static str| {
+            let config = config.clone();
+            let blocked_nicks = blocked_nicks.clone();
+
+            async move {
+                let message = message_from(nick, &config.irc.channel, "!np");
+                incoming_message_to_action(&message, &config.irc.channel, &config, &blocked_nicks)
+                    .await
+            }
+        };
+
+        assert!(action("spammer").await.is_none());
+        assert!(action("troll").await.is_none());
+        assert!(matches!(
+            action("someone").await,
+            Some(Event::Playback(PlaybackAction::NowPlaying))
+        ));
+        assert!(matches!(
+            action("admin").await,
+            Some(Event::Playback(PlaybackAction::NowPlaying))
+        ));
+    }
+
+    #[tokio::test]
+    async fn message_outside_channel_produces_no_events() {
+        let config = crate::config::example();
+        let message = message_from("someone", "#elsewhere", "!np");
+
+        let action =
+            incoming_message_to_action(&message, &config.irc.channel, &config, &Default::default())
+                .await;
+
+        assert!(action.is_none());
+    }
+
+    #[test]
+    fn parse_tag_joins_words_and_strips_prefix() {
+        assert_eq!(
+            parse_tag("Love songs".split_whitespace()),
+            Some("Love songs".into())
+        );
+        assert_eq!(
+            parse_tag("tag:drinking".split_whitespace()),
+            Some("drinking".into())
+        );
+        assert_eq!(
+            parse_tag("tag: drinking".split_whitespace()),
+            Some("drinking".into())
+        );
+        assert_eq!(parse_tag("tag:".split_whitespace()), None);
+        assert_eq!(parse_tag("".split_whitespace()), None);
+    }
+
+    #[test]
+    fn full_queue_drops_oldest_message_and_truncates() {
+        let mut pending = PendingMessages::default();
+
+        for i in 0..MESSAGE_QUEUE_CAPACITY + 1 {
+            pending.push(privmsg(&i.to_string()));
+        }
+
+        assert_eq!(pending.commands.len(), MESSAGE_QUEUE_CAPACITY);
+        assert_eq!(
+            pending.pop("#channel"),
+            Some(Command::NOTICE(
+                "#channel".to_string(),
+                "…(truncated)".to_string()
+            ))
+        );
+        assert_eq!(pending.pop("#channel"), Some(privmsg("1")));
+    }
+
+    #[test]
+    fn full_queue_keeps_non_message_commands() {
+        let mut pending = PendingMessages::default();
+        let topic = Command::TOPIC("#channel".to_string(), Some("topic".to_string()));
+
+        pending.push(topic.clone());
+        for i in 0..MESSAGE_QUEUE_CAPACITY {
+            pending.push(privmsg(&i.to_string()));
+        }
+
+        assert_eq!(pending.commands.len(), MESSAGE_QUEUE_CAPACITY);
+        assert!(pending.truncated);
+        assert_eq!(pending.commands.front(), Some(&topic));
+        assert_eq!(pending.commands.get(1), Some(&privmsg("1")));
+    }
+}
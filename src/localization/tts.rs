@@ -0,0 +1,136 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+
+lazy_static! {
+    static ref PARENTHETICAL_RE: Regex = Regex::new(r"\([^)]*\)|\[[^\]]*\]").unwrap();
+    static ref WHITESPACE_RE: Regex = Regex::new(r"\s+").unwrap();
+}
+
+/// Default abbreviations expanded by [normalize_title]
+pub fn default_abbreviations() -> HashMap<String, String> {
+    [
+        ("w/out", "without"),
+        ("w/", "with"),
+        ("feat.", "featuring"),
+        ("ft.", "featuring"),
+        ("vs.", "versus"),
+        ("AC/DC", "AC DC"),
+    ]
+    .into_iter()
+    .map(|(abbr, expansion)| (abbr.to_string(), expansion.to_string()))
+    .collect()
+}
+
+/// Word used in place of "&" for the given language
+fn and_word(language: &str) -> &'static str {
+    if language.starts_with("sv") {
+        "och"
+    } else if language.starts_with("fi") {
+        "ja"
+    } else {
+        "and"
+    }
+}
+
+/// Rewrites a song title into something eSpeak pronounces sensibly.
+///
+/// Removes parenthetical content, expands abbreviations, replaces "&" with
+/// the language's word for "and" and trims surrounding punctuation.
+pub fn normalize_title(
+    title: &str,
+    language: &str,
+    abbreviations: &HashMap<String, String>,
+) -> String {
+    let mut title = PARENTHETICAL_RE.replace_all(title, " ").to_string();
+
+    // Expand longer abbreviations first so that e.g. "w/out" wins over "w/"
+    let mut abbreviations: Vec<_> = abbreviations.iter().collect();
+    abbreviations.sort_by_key(|(abbr, _)| std::cmp::Reverse(abbr.len()));
+
+    for (abbr, expansion) in abbreviations {
+        let word_end = if abbr.ends_with(|c: char| c.is_alphanumeric()) {
+            r"\b"
+        } else {
+            ""
+        };
+        let re = Regex::new(&format!(r"(?i)(^|\s){}{word_end}", regex::escape(abbr)));
+
+        match re {
+            Ok(re) => {
+                title = re
+                    .replace_all(&title, format!("${{1}}{expansion} "))
+                    .to_string()
+            }
+            Err(e) => warn!("Invalid TTS abbreviation {abbr:?}: {:?}", e),
+        }
+    }
+
+    let title = title
+        .replace('&', &format!(" {} ", and_word(language)))
+        .replace('/', " ");
+
+    WHITESPACE_RE
+        .replace_all(&title, " ")
+        .trim_matches(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(title: &str, language: &str) -> String {
+        normalize_title(title, language, &default_abbreviations())
+    }
+
+    #[test]
+    fn expands_abbreviations() {
+        assert_eq!(normalize("w/out U", "en"), "without U");
+        assert_eq!(normalize("Back w/ you", "en"), "Back with you");
+        assert_eq!(
+            normalize("Song feat. Someone", "en"),
+            "Song featuring Someone"
+        );
+        assert_eq!(
+            normalize("AC/DC - Back in Black", "en"),
+            "AC DC - Back in Black"
+        );
+    }
+
+    #[test]
+    fn replaces_ampersand_in_the_tts_language() {
+        assert_eq!(normalize("Rock & Roll", "sv"), "Rock och Roll");
+        assert_eq!(normalize("Rock & Roll", "fi"), "Rock ja Roll");
+        assert_eq!(normalize("Rock & Roll", "en"), "Rock and Roll");
+    }
+
+    #[test]
+    fn removes_parenthetical_content_and_surrounding_punctuation() {
+        assert_eq!(
+            normalize("Rock & Roll (Live) [2010 Remaster]", "en"),
+            "Rock and Roll"
+        );
+        assert_eq!(normalize("Vad i allsin dar?", "sv"), "Vad i allsin dar");
+        assert_eq!(normalize("...Helan går!", "sv"), "Helan går");
+    }
+
+    #[test]
+    fn keeps_plain_titles_unchanged() {
+        assert_eq!(normalize("Fjärran han dröjer", "sv"), "Fjärran han dröjer");
+    }
+
+    #[test]
+    fn uses_configured_abbreviations() {
+        let abbreviations = [("TF".to_string(), "Teknologföreningen".to_string())].into();
+
+        assert_eq!(
+            normalize_title("TF sången", "sv", &abbreviations),
+            "Teknologföreningen sången"
+        );
+        assert_eq!(
+            normalize_title("TFs sång", "sv", &abbreviations),
+            "TFs sång"
+        );
+    }
+}
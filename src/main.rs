@@ -6,8 +6,11 @@ mod config;
 mod constants;
 mod event;
 mod irc;
+mod localization;
+mod metrics;
 mod mixer;
 mod net;
+mod persist;
 mod playback;
 mod songbook;
 mod songleader;
@@ -24,8 +27,8 @@ async fn main() -> anyhow::Result<()> {
 
     // let sine_source1 = sources::sine::init(440.0);
     // let sine_source2 = sources::sine::init(640.0);
-    let espeak_source = sources::espeak::init(&bus);
-    let symphonia_source = sources::symphonia::init(&bus).await?;
+    let espeak_source = sources::espeak::init(&bus, &config);
+    let symphonia_source = sources::symphonia::init(&bus, &config).await?;
 
     let mixer_output = mixer::init(
         &bus,
@@ -35,13 +38,28 @@ async fn main() -> anyhow::Result<()> {
             // sine_source1,
             // sine_source2
         ],
+        &config.mixer,
     )?;
 
     youtube::init().await?;
-    playback::init(&bus).await;
+    // Song requests fail until yt-dlp works, but everything else does not
+    // need it
+    let ytdlp_available = match youtube::check_ytdlp_available(&config.youtube).await {
+        Ok(yt_dlp_version) => {
+            info!("Using yt-dlp version {yt_dlp_version}");
+            true
+        }
+        Err(e) => {
+            warn!("{e}");
+            false
+        }
+    };
+    // Start serving before connecting to IRC so the health endpoint sees
+    // the connection event
+    net::init(mixer_output, &bus, &config, ytdlp_available);
+    playback::init(&bus, &config).await;
     irc::init(&bus, &config).await?;
     songleader::init(&bus, &config).await;
-    net::init(mixer_output);
     event::debug(&bus);
 
     // stdin::init(&bus);
@@ -0,0 +1,191 @@
+use crate::event::EventBus;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Time spent running yt-dlp to fetch song info or media URLs
+pub static YOUTUBE_FETCH_DURATION: Histogram = Histogram::new(
+    "youtube_fetch_duration_seconds",
+    "Time spent waiting for yt-dlp",
+    &[0.5, 1.0, 2.0, 5.0, 10.0, 30.0],
+);
+
+/// Time spent decoding a song from start to end of file
+pub static SYMPHONIA_DECODE_DURATION: Histogram = Histogram::new(
+    "symphonia_decode_duration_seconds",
+    "Time spent decoding a song until the end of file",
+    &[1.0, 5.0, 10.0, 30.0, 60.0, 120.0],
+);
+
+/// Number of IRC commands waiting in the outgoing message queue
+pub static IRC_MESSAGE_QUEUE_PENDING: Gauge = Gauge::new(
+    "irc_message_queue_pending",
+    "Number of IRC commands waiting to be sent",
+);
+
+/// Prometheus style gauge holding a single non-negative value
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU64,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set(&self, value: u64) {
+        self.value.store(value, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String) {
+        let value = self.value.load(Ordering::Relaxed);
+        render_value(out, self.name, self.help, "gauge", value);
+    }
+}
+
+/// Prometheus style histogram with fixed bucket upper bounds
+pub struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    data: Mutex<HistogramData>,
+}
+
+#[derive(Default)]
+struct HistogramData {
+    /// Number of observations per bucket, not cumulative. Empty until the
+    /// first observation.
+    bucket_counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub const fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Self {
+        Self {
+            name,
+            help,
+            buckets,
+            data: Mutex::new(HistogramData {
+                bucket_counts: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let mut data = self.data.lock().unwrap();
+
+        data.bucket_counts.resize(self.buckets.len(), 0);
+        if let Some(index) = self.buckets.iter().position(|bound| value <= *bound) {
+            data.bucket_counts[index] += 1;
+        }
+
+        data.sum += value;
+        data.count += 1;
+    }
+
+    /// Observes the seconds elapsed since `start`
+    pub fn observe_since(&self, start: Instant) {
+        self.observe(start.elapsed().as_secs_f64());
+    }
+
+    /// Writes the histogram in the Prometheus text exposition format
+    fn render(&self, out: &mut String) {
+        let data = self.data.lock().unwrap();
+        let name = self.name;
+
+        writeln!(out, "# HELP {name} {}", self.help).ok();
+        writeln!(out, "# TYPE {name} histogram").ok();
+
+        let mut cumulative = 0;
+        for (index, bound) in self.buckets.iter().enumerate() {
+            cumulative += data.bucket_counts.get(index).copied().unwrap_or(0);
+            writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}").ok();
+        }
+
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", data.count).ok();
+        writeln!(out, "{name}_sum {}", data.sum).ok();
+        writeln!(out, "{name}_count {}", data.count).ok();
+    }
+}
+
+/// Writes a single unlabeled counter or gauge sample
+fn render_value(out: &mut String, name: &str, help: &str, kind: &str, value: u64) {
+    writeln!(out, "# HELP {name} {help}").ok();
+    writeln!(out, "# TYPE {name} {kind}").ok();
+    writeln!(out, "{name} {value}").ok();
+}
+
+/// Renders all metrics in the Prometheus text exposition format
+pub fn render(bus: &EventBus) -> String {
+    let mut out = String::new();
+
+    YOUTUBE_FETCH_DURATION.render(&mut out);
+    SYMPHONIA_DECODE_DURATION.render(&mut out);
+    IRC_MESSAGE_QUEUE_PENDING.render(&mut out);
+
+    render_value(
+        &mut out,
+        "event_bus_subscribers",
+        "Number of subscribers on the event bus",
+        "gauge",
+        bus.subscriber_count() as u64,
+    );
+    render_value(
+        &mut out,
+        "event_bus_lagged_total",
+        "Total number of events skipped by lagging event bus subscribers",
+        "counter",
+        bus.lagged_total(),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_renders_cumulative_buckets_sum_and_count() {
+        let histogram = Histogram::new("test_seconds", "Test histogram", &[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(10.0);
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+
+        assert_eq!(
+            out,
+            "# HELP test_seconds Test histogram\n\
+             # TYPE test_seconds histogram\n\
+             test_seconds_bucket{le=\"1\"} 1\n\
+             test_seconds_bucket{le=\"5\"} 2\n\
+             test_seconds_bucket{le=\"+Inf\"} 3\n\
+             test_seconds_sum 12.5\n\
+             test_seconds_count 3\n"
+        );
+    }
+
+    #[test]
+    fn empty_histogram_renders_zero_counts() {
+        let histogram = Histogram::new("test_seconds", "Test histogram", &[1.0]);
+
+        let mut out = String::new();
+        histogram.render(&mut out);
+
+        assert!(out.contains("test_seconds_bucket{le=\"1\"} 0\n"));
+        assert!(out.contains("test_seconds_sum 0\n"));
+        assert!(out.contains("test_seconds_count 0\n"));
+    }
+}
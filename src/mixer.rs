@@ -1,4 +1,5 @@
 use crate::{
+    config::MixerConfig,
     constants::SAMPLE_RATE,
     event::{Event, EventBus},
 };
@@ -10,32 +11,55 @@ const TARGET_CHUNK_SIZE: usize = 128;
 #[derive(Clone, Debug)]
 pub enum MixerAction {
     DuckSecondaryChannels,
+    /// Ducks secondary channels to a fraction of their normal volume instead
+    /// of the configured ducked volume
+    DuckSecondaryChannelsTo(f64),
     UnduckSecondaryChannels,
     SetSecondaryChannelVolume(f64),
-    SetSecondaryChannelDuckedVolume(f64),
+    /// Sets the volume of secondary channels while ducked
+    SetDuckLevel(f64),
+    /// Sets the number of milliseconds over which secondary channels fade
+    /// between volumes
+    SetDuckFadeDuration(u64),
 }
 
 const PRIMARY_CHANNEL_VOLUME: f64 = 1.25;
 const INIT_SECONDARY_CHANNEL_VOLUME_TARGET: f64 = 0.75;
-const INIT_SECONDARY_CHANNEL_VOLUME_TARGET_DUCKED: f64 = 0.2;
 
 pub type Sample = (i16, i16);
 pub type MixerInput = mpsc::Receiver<Sample>;
 pub type MixerOutput = watch::Receiver<Vec<Sample>>;
 
-pub fn init(bus: &EventBus, mut sources: Vec<MixerInput>) -> Result<MixerOutput> {
+/// Number of samples a fade between volumes takes
+fn fade_samples(fade_ms: u64) -> f64 {
+    (fade_ms as f64 / 1000.0 * SAMPLE_RATE as f64).max(1.0)
+}
+
+pub fn init(
+    bus: &EventBus,
+    mut sources: Vec<MixerInput>,
+    config: &MixerConfig,
+) -> Result<MixerOutput> {
     let (tx, rx) = watch::channel(Default::default());
 
     let bus = bus.clone();
+    let config = config.clone();
     tokio::spawn(async move {
         let start_time = std::time::Instant::now();
         let mut sample_send_count = 0;
 
         let mut current_secondary_volume = INIT_SECONDARY_CHANNEL_VOLUME_TARGET;
         let mut duck_secondary_channels = false;
+        let mut duck_fraction = None;
 
         let mut adjusted_secondary_volume = INIT_SECONDARY_CHANNEL_VOLUME_TARGET;
-        let mut adjusted_secondary_volume_ducked = INIT_SECONDARY_CHANNEL_VOLUME_TARGET_DUCKED;
+        let mut adjusted_secondary_volume_ducked = config.duck_level;
+        let mut fade_ms = config.duck_fade_ms;
+
+        // Volume step per sample, recalculated whenever the target changes so
+        // that every fade takes fade_ms regardless of its distance
+        let mut fade_target = current_secondary_volume;
+        let mut fade_step = 0.0;
 
         let mut subscriber = bus.subscribe();
 
@@ -44,6 +68,11 @@ pub fn init(bus: &EventBus, mut sources: Vec<MixerInput>) -> Result<MixerOutput>
                 match event {
                     Event::Mixer(MixerAction::DuckSecondaryChannels) => {
                         duck_secondary_channels = true;
+                        duck_fraction = None;
+                    }
+                    Event::Mixer(MixerAction::DuckSecondaryChannelsTo(fraction)) => {
+                        duck_secondary_channels = true;
+                        duck_fraction = Some(fraction);
                     }
                     Event::Mixer(MixerAction::UnduckSecondaryChannels) => {
                         duck_secondary_channels = false;
@@ -51,9 +80,12 @@ pub fn init(bus: &EventBus, mut sources: Vec<MixerInput>) -> Result<MixerOutput>
                     Event::Mixer(MixerAction::SetSecondaryChannelVolume(volume)) => {
                         adjusted_secondary_volume = volume;
                     }
-                    Event::Mixer(MixerAction::SetSecondaryChannelDuckedVolume(volume)) => {
+                    Event::Mixer(MixerAction::SetDuckLevel(volume)) => {
                         adjusted_secondary_volume_ducked = volume;
                     }
+                    Event::Mixer(MixerAction::SetDuckFadeDuration(ms)) => {
+                        fade_ms = ms;
+                    }
                     _ => {}
                 }
             }
@@ -68,12 +100,17 @@ pub fn init(bus: &EventBus, mut sources: Vec<MixerInput>) -> Result<MixerOutput>
             let chunk_size = (expected_sent_samples - sample_send_count) as usize;
             let mut chunk = Vec::with_capacity(chunk_size);
 
-            let target_secondary_volume = if duck_secondary_channels {
-                adjusted_secondary_volume_ducked
-            } else {
-                adjusted_secondary_volume
+            let target_secondary_volume = match (duck_secondary_channels, duck_fraction) {
+                (true, Some(fraction)) => adjusted_secondary_volume * fraction,
+                (true, None) => adjusted_secondary_volume_ducked,
+                (false, _) => adjusted_secondary_volume,
             };
 
+            if target_secondary_volume != fade_target {
+                fade_target = target_secondary_volume;
+                fade_step = (fade_target - current_secondary_volume).abs() / fade_samples(fade_ms);
+            }
+
             for _ in 0..chunk_size {
                 let mut left: i16 = 0;
                 let mut right: i16 = 0;
@@ -81,14 +118,11 @@ pub fn init(bus: &EventBus, mut sources: Vec<MixerInput>) -> Result<MixerOutput>
                 let secondary_volume_delta: f64 =
                     target_secondary_volume - current_secondary_volume;
 
-                // Slowly fade secondary channels towards the target volume
-                let correction_rate = 0.0001;
-                if secondary_volume_delta.abs() < 0.001 {
+                // Linearly fade secondary channels towards the target volume
+                if secondary_volume_delta.abs() <= fade_step {
                     current_secondary_volume = target_secondary_volume;
-                } else if secondary_volume_delta.is_sign_positive() {
-                    current_secondary_volume += correction_rate;
                 } else {
-                    current_secondary_volume -= correction_rate;
+                    current_secondary_volume += fade_step.copysign(secondary_volume_delta);
                 };
 
                 let mut first_source = true;
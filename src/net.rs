@@ -1,24 +1,147 @@
+use crate::config::{Config, NetConfig};
 use crate::constants::{BIT_DEPTH, CHANNELS, SAMPLE_RATE};
-use crate::mixer::MixerOutput;
+use crate::event::{Event, EventBus};
+use crate::irc::IrcAction;
+use crate::metrics;
+use crate::mixer::{MixerOutput, Sample};
 use anyhow::Result;
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{BigEndian, LittleEndian, WriteBytesExt};
 use hound::{SampleFormat, WavSpec};
 use std::net::SocketAddr;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpListener;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 
-const HTTP: bool = false;
-const LISTEN_ADDR: &str = "0.0.0.0:7878";
+/// Maximum size of an HTTP request head that we are willing to read
+const MAX_REQUEST_HEAD_LEN: usize = 8192;
+
+/// Subsystem liveness reported by the health endpoint. Kept up to date from
+/// bus events so that health requests never block.
+#[derive(Default)]
+struct Health {
+    irc_connected: AtomicBool,
+    youtube_available: AtomicBool,
+    queue_depth: AtomicUsize,
+}
+
+impl Health {
+    fn to_response(&self) -> String {
+        let irc_connected = self.irc_connected.load(Ordering::Relaxed);
+
+        let body = serde_json::json!({
+            "irc": if irc_connected { "connected" } else { "disconnected" },
+            // There is no Discord integration, IRC is the only platform
+            "discord": "unavailable",
+            "youtube_available": self.youtube_available.load(Ordering::Relaxed),
+            "queue_depth": self.queue_depth.load(Ordering::Relaxed),
+        })
+        .to_string();
+
+        let status = if irc_connected {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        };
+
+        format!(
+            "HTTP/1.0 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        )
+    }
+}
+
+/// Updates [Health] from bus events
+fn start_health_event_loop(bus: EventBus, health: Arc<Health>) {
+    tokio::spawn(async move {
+        let mut bus = bus.subscribe();
+
+        loop {
+            let event = bus.recv().await;
+
+            match event {
+                Event::Irc(IrcAction::Connected) => {
+                    health.irc_connected.store(true, Ordering::Relaxed)
+                }
+                Event::Irc(IrcAction::Disconnected) => {
+                    health.irc_connected.store(false, Ordering::Relaxed)
+                }
+                Event::Irc(IrcAction::PlaybackTopicInfo { queue_len, .. }) => {
+                    health.queue_depth.store(queue_len, Ordering::Relaxed)
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+/// Audio formats that can be streamed to clients
+#[derive(Clone, Copy, PartialEq)]
+enum StreamFormat {
+    /// WAV header followed by little-endian 16-bit PCM
+    Wav,
+
+    /// Headerless big-endian 16-bit PCM as specified for audio/L16
+    L16,
+}
+
+impl StreamFormat {
+    /// Picks the stream format based on the requested HTTP path
+    fn from_path(path: &str) -> Self {
+        if path.ends_with(".pcm") || path.ends_with("/l16") {
+            StreamFormat::L16
+        } else {
+            StreamFormat::Wav
+        }
+    }
+
+    fn content_type(&self) -> String {
+        match self {
+            StreamFormat::Wav => "audio/wav".to_string(),
+            StreamFormat::L16 => format!("audio/L16;rate={SAMPLE_RATE};channels={CHANNELS}"),
+        }
+    }
+
+    fn encode(&self, samples: Vec<Sample>) -> Vec<u8> {
+        let mut data: Vec<u8> = Vec::with_capacity(samples.len() * 4);
+
+        for (left, right) in samples {
+            match self {
+                StreamFormat::Wav => {
+                    WriteBytesExt::write_i16::<LittleEndian>(&mut data, left).ok();
+                    WriteBytesExt::write_i16::<LittleEndian>(&mut data, right).ok();
+                }
+                StreamFormat::L16 => {
+                    WriteBytesExt::write_i16::<BigEndian>(&mut data, left).ok();
+                    WriteBytesExt::write_i16::<BigEndian>(&mut data, right).ok();
+                }
+            }
+        }
+
+        data
+    }
+}
+
+/// Starts the HTTP stream server. `ytdlp_available` is the result of the
+/// yt-dlp check done at startup and is reported by the health endpoint.
+pub fn init(source: MixerOutput, bus: &EventBus, config: &Config, ytdlp_available: bool) {
+    let config = config.net.clone();
+    let health = Arc::new(Health::default());
+    health
+        .youtube_available
+        .store(ytdlp_available, Ordering::Relaxed);
+
+    start_health_event_loop(bus.clone(), health.clone());
+    let bus = bus.clone();
 
-pub fn init(source: MixerOutput) {
     tokio::spawn(async move {
         // Create a TCP listener that binds to the configured address
-        let listener = TcpListener::bind(LISTEN_ADDR).await.unwrap();
-        info!("Listening on {LISTEN_ADDR}");
+        let listener = TcpListener::bind(&config.listen_addr).await.unwrap();
+        info!("Listening on {}", config.listen_addr);
 
         loop {
             // Accept a connection and get the stream
-            let result = accept(&listener, &source).await;
+            let result = accept(&listener, &source, &config, &health, &bus).await;
 
             match result {
                 Ok(addr) => info!("Accepted connection from {}", addr),
@@ -28,13 +151,47 @@ pub fn init(source: MixerOutput) {
     });
 }
 
-async fn accept(listener: &TcpListener, source: &MixerOutput) -> Result<SocketAddr> {
+/// Reads the HTTP request head and returns the requested path
+async fn read_request_path(stream: &mut TcpStream) -> Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+
+        if n == 0 || head.len() > MAX_REQUEST_HEAD_LEN {
+            anyhow::bail!("Invalid HTTP request");
+        }
+
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let path = head
+        .lines()
+        .next()
+        .and_then(|request_line| request_line.split_whitespace().nth(1))
+        .unwrap_or("/");
+
+    Ok(path.to_string())
+}
+
+async fn accept(
+    listener: &TcpListener,
+    source: &MixerOutput,
+    config: &NetConfig,
+    health: &Arc<Health>,
+    bus: &EventBus,
+) -> Result<SocketAddr> {
     let (mut stream, addr) = listener.accept().await?;
 
     stream.set_nodelay(true)?;
 
     // Spawn a new task to handle incoming samples
     let mut source = source.clone();
+    let http = config.http_stream;
+    let health = health.clone();
+    let bus = bus.clone();
 
     // Spawn a new task to handle the connection
     tokio::spawn(async move {
@@ -46,23 +203,64 @@ async fn accept(listener: &TcpListener, source: &MixerOutput) -> Result<SocketAd
             sample_format: SampleFormat::Int,
         };
 
-        if HTTP {
+        let format = if http {
+            let path = match read_request_path(&mut stream).await {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!("Failed to read HTTP request: {}", e);
+                    return;
+                }
+            };
+
+            if path == "/health" {
+                let response = health.to_response();
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    warn!("Failed to write health response: {}", e);
+                }
+                return;
+            }
+
+            if path == "/metrics" {
+                let body = metrics::render(&bus);
+                let response = format!(
+                    "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+                    body.len()
+                );
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    warn!("Failed to write metrics response: {}", e);
+                }
+                return;
+            }
+
+            let format = StreamFormat::from_path(&path);
+
             let cors_headers = "Access-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: GET\r\nAccess-Control-Allow-Headers: *\r\n";
+            // Icecast style headers so that stream players recognize the
+            // response as a live stream
+            let icy_headers = format!("icy-name: {}\r\n", env!("CARGO_PKG_NAME"));
             // Write HTTP headers to the stream
-            let header =
-                format!("HTTP/1.1 200 OK\r\n{cors_headers}Content-Type: audio/wav\r\n\r\n");
+            let header = format!(
+                "HTTP/1.0 200 OK\r\n{cors_headers}{icy_headers}Cache-Control: no-cache\r\nContent-Type: {}\r\n\r\n",
+                format.content_type()
+            );
             if let Err(e) = stream.write_all(header.as_bytes()).await {
                 warn!("Failed to write HTTP header to stream: {}", e);
                 return;
             };
-        }
 
-        // Write the wav header to the stream using the hound crate
-        // This will allow players to recognize the stream as a wav file
-        let header = spec.into_header_for_infinite_file();
-        if let Err(e) = stream.write_all(&header[..]).await {
-            warn!("Failed to write wav header to stream: {}", e);
-            return;
+            format
+        } else {
+            StreamFormat::Wav
+        };
+
+        if format == StreamFormat::Wav {
+            // Write the wav header to the stream using the hound crate
+            // This will allow players to recognize the stream as a wav file
+            let header = spec.into_header_for_infinite_file();
+            if let Err(e) = stream.write_all(&header[..]).await {
+                warn!("Failed to write wav header to stream: {}", e);
+                return;
+            }
         }
 
         // Create a loop to write audio samples to the stream
@@ -73,14 +271,9 @@ async fn accept(listener: &TcpListener, source: &MixerOutput) -> Result<SocketAd
                 .expect("Expected mixer channel to never close");
 
             let samples = source.borrow_and_update().clone();
-            let mut wav_data: Vec<u8> = Vec::with_capacity(samples.len() * 2);
-
-            for (left, right) in samples {
-                WriteBytesExt::write_i16::<LittleEndian>(&mut wav_data, left).ok();
-                WriteBytesExt::write_i16::<LittleEndian>(&mut wav_data, right).ok();
-            }
+            let data = format.encode(samples);
 
-            if let Err(e) = stream.write_all(wav_data.as_slice()).await {
+            if let Err(e) = stream.write_all(data.as_slice()).await {
                 warn!("Failed to write sample to stream: {}", e);
                 break;
             }
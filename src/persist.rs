@@ -0,0 +1,117 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{sync::RwLock, task::JoinHandle, time::sleep};
+
+/// Distinguishes the temporary files of concurrent writes to the same path
+static WRITE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes `contents` to a temporary file next to `path` and renames it over
+/// `path`, so that a crash mid-write never leaves a truncated state file
+pub async fn write_atomically(path: &Path, contents: String) -> std::io::Result<()> {
+    let n = WRITE_COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(format!(".{n}.tmp"));
+
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, path).await
+}
+
+/// Calls `persist` with the value behind `lock` every `interval`, so that the
+/// state is persisted even when nothing has changed
+pub fn start_heartbeat<T: Send + Sync + 'static>(
+    lock: Arc<RwLock<T>>,
+    interval: Duration,
+    persist: fn(&T),
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            sleep(interval).await;
+            persist(&*lock.read().await);
+        }
+    })
+}
+
+/// Joins a file name given by a user onto `dir`. Returns None for anything
+/// but a plain file name, so that the result can't point outside of `dir`.
+pub fn file_in_dir(dir: &Path, file_name: &str) -> Option<PathBuf> {
+    let is_plain_name = !file_name.is_empty()
+        && file_name != "."
+        && !file_name.contains("..")
+        && !file_name.contains(['/', '\\']);
+
+    is_plain_name.then(|| dir.join(file_name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[tokio::test]
+    async fn write_atomically_replaces_contents() {
+        let dir = std::env::temp_dir().join("irc-sitz-test-write_atomically");
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("state.json");
+
+        write_atomically(&path, "first".to_string()).await.unwrap();
+        write_atomically(&path, "second".to_string()).await.unwrap();
+
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "second");
+
+        // No temporary files are left behind
+        let mut entries = tokio::fs::read_dir(&dir).await.unwrap();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            assert_eq!(entry.file_name(), "state.json");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heartbeat_persists_every_interval() {
+        let persisted = Arc::new(RwLock::new(AtomicUsize::new(0)));
+        let heartbeat = start_heartbeat(persisted.clone(), Duration::from_secs(10), |count| {
+            count.fetch_add(1, Ordering::Relaxed);
+        });
+
+        sleep(Duration::from_secs(5)).await;
+        assert_eq!(persisted.read().await.load(Ordering::Relaxed), 0);
+
+        sleep(Duration::from_secs(30)).await;
+        assert_eq!(persisted.read().await.load(Ordering::Relaxed), 3);
+
+        heartbeat.abort();
+    }
+
+    #[test]
+    fn file_in_dir_accepts_plain_names() {
+        assert_eq!(
+            file_in_dir(Path::new("dumps"), "buffer.wav"),
+            Some(PathBuf::from("dumps/buffer.wav"))
+        );
+    }
+
+    #[test]
+    fn file_in_dir_rejects_paths() {
+        for file_name in [
+            "",
+            ".",
+            "..",
+            "../Config.toml",
+            "/etc/passwd",
+            "a/b.wav",
+            "a\\b",
+        ] {
+            assert_eq!(
+                file_in_dir(Path::new("dumps"), file_name),
+                None,
+                "{file_name}"
+            );
+        }
+    }
+}
@@ -1,14 +1,31 @@
 use crate::{
+    config::{Config, PlaybackConfig, YoutubeConfig},
     event::{Event, EventBus},
     irc::IrcAction,
+    persist,
+    songbook::SongbookSong,
     sources::symphonia::SymphoniaAction,
+    youtube::get_yt_song_info,
 };
+use rand::seq::SliceRandom;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::RwLock;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{sync::RwLock, time::sleep};
 
-const PLAYBACK_STATE_FILE: &str = "playback_state.json";
 pub const MAX_SONG_DURATION: Duration = Duration::from_secs(10 * 60);
+const VOTE_SORT_DEBOUNCE: Duration = Duration::from_secs(5);
+const MAX_LISTED_VOTES: usize = 5;
+
+/// Number of songs in a row that may fail to play before playback is paused,
+/// so that e.g. a YouTube outage doesn't skip through the whole queue
+const MAX_CONSECUTIVE_FAILURES: usize = 3;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Song {
@@ -18,6 +35,73 @@ pub struct Song {
     pub channel: String,
     pub duration: u64,
     pub queued_by: String,
+
+    /// Unix timestamp in milliseconds of when the song was enqueued, used to
+    /// break ties when sorting the queue by votes
+    #[serde(default)]
+    pub enqueued_at: u64,
+
+    /// Start and end of the segment to play in seconds, if only part of the
+    /// song should be played
+    #[serde(default)]
+    pub trim: Option<(u64, u64)>,
+
+    /// Start and end in seconds of SponsorBlock segments to skip during
+    /// playback
+    #[serde(default)]
+    pub skip_segments: Vec<(f64, f64)>,
+
+    /// Lowercase tags and categories of the song, used to filter the queue
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Platform that the song was queued from
+    #[serde(default)]
+    pub platform: Platform,
+}
+
+/// Chat platform that a song was queued from
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    Irc,
+
+    /// Queued by the bot itself, also used for songs queued before
+    /// platforms were tracked
+    #[default]
+    Bot,
+}
+
+impl Platform {
+    /// Name of the platform as used in `max_songs_per_platform`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Irc => "irc",
+            Platform::Bot => "bot",
+        }
+    }
+}
+
+/// Converts a songbook song for the playback queue, e.g. to play a backing
+/// track. The duration is unknown and left as 0. The result is only playable
+/// if the song's `url` points to media, such as a YouTube video, rather than
+/// a songbook page.
+impl From<SongbookSong> for Song {
+    fn from(song: SongbookSong) -> Self {
+        Song {
+            url: song.url.unwrap_or_default(),
+            title: song.title.unwrap_or_else(|| song.id.clone()),
+            id: song.id,
+            channel: song.book.unwrap_or_else(|| "Songbook".to_string()),
+            duration: 0,
+            queued_by: song.queued_by.unwrap_or_else(|| "admin".to_string()),
+            enqueued_at: 0,
+            trim: None,
+            skip_segments: vec![],
+            tags: song.tags.iter().map(|tag| tag.to_lowercase()).collect(),
+            platform: Platform::Bot,
+        }
+    }
 }
 
 impl PartialEq for Song {
@@ -26,17 +110,132 @@ impl PartialEq for Song {
     }
 }
 
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SongVotes {
+    /// Users that have upvoted the song
+    pub up: HashSet<String>,
+
+    /// Users that have downvoted the song
+    pub down: HashSet<String>,
+}
+
+impl SongVotes {
+    pub fn score(&self) -> i64 {
+        self.up.len() as i64 - self.down.len() as i64
+    }
+}
+
+/// Tracks how far into the current song playback has progressed
+#[derive(Clone, Debug, Default)]
+struct PlaybackProgress {
+    /// When playback was last started or resumed, `None` while paused
+    resumed_at: Option<Instant>,
+
+    /// Time played before the last pause
+    elapsed_before_pause: Duration,
+}
+
+impl PlaybackProgress {
+    fn restart(&mut self) {
+        self.resumed_at = Some(Instant::now());
+        self.elapsed_before_pause = Duration::ZERO;
+    }
+
+    fn pause(&mut self) {
+        if let Some(resumed_at) = self.resumed_at.take() {
+            self.elapsed_before_pause += resumed_at.elapsed();
+        }
+    }
+
+    fn resume(&mut self) {
+        if self.resumed_at.is_none() {
+            self.resumed_at = Some(Instant::now());
+        }
+    }
+
+    /// Continues counting from `position`, keeping the paused state
+    fn seek(&mut self, position: Duration) {
+        self.elapsed_before_pause = position;
+
+        if self.resumed_at.is_some() {
+            self.resumed_at = Some(Instant::now());
+        }
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.elapsed_before_pause
+            + self
+                .resumed_at
+                .map(|resumed_at| resumed_at.elapsed())
+                .unwrap_or_default()
+    }
+}
+
+/// Current unix time in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Formats seconds as "m:ss"
+fn fmt_duration(secs: u64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Where in the queue a song is added
+#[derive(Clone, Copy, Debug)]
+pub enum QueuePosition {
+    /// At the end of the queue
+    End,
+
+    /// At a position in the queue, after the current song
+    At(usize),
+
+    /// Immediately after the current song
+    AfterCurrent,
+
+    /// Before the last song in the queue
+    BeforeLast,
+}
+
 #[derive(Clone, Debug)]
 pub enum PlaybackAction {
     /// Add song at the end of the queue
     Enqueue { song: Song },
 
+    /// Insert song immediately after the current song
+    InsertAfterCurrent { song: Song },
+
+    /// Insert song before the last song in the queue
+    InsertBeforeLast { song: Song },
+
+    /// Fetch song info from YouTube and add the song to the queue at
+    /// `position`
+    EnqueueUrl {
+        url_or_search_terms: String,
+        queued_by: String,
+        platform: Platform,
+        position: QueuePosition,
+    },
+
     /// Player reached end of song
     EndOfSong,
 
+    /// Playing the song with the given id failed. Ignored unless it is still
+    /// the current song.
+    SongFailed { id: String, error: String },
+
     /// List either the first items in a queue or an item at a specific position
     ListQueue { offset: Option<usize> },
 
+    /// Show the latest song in the queue queued by nick
+    ListQueueByNick { nick: String },
+
+    /// Show the currently playing song and its progress
+    NowPlaying,
+
     /// Removes song by position
     RmSongByPos { pos: usize },
 
@@ -52,8 +251,72 @@ pub enum PlaybackAction {
     /// Play previous song
     Prev,
 
+    /// Go back n songs in the playback history
+    PrevN { n: NonZeroUsize },
+
+    /// Go back to the song at index in the playback history
+    JumpToPlayed { index: usize },
+
     /// Play next song
     Next,
+
+    /// Upvote a queued song by id or url
+    Upvote { song_id: String, user: String },
+
+    /// Downvote a queued song by id or url
+    Downvote { song_id: String, user: String },
+
+    /// List the queued songs with the highest vote scores
+    ListVotes,
+
+    /// Probe the actual duration of a queued song, for when the metadata was
+    /// incorrect
+    RefreshDuration { id: String },
+
+    /// Update the duration of a queued song
+    SetDuration { id: String, duration: u64 },
+
+    /// Only play a segment of a queued song
+    Trim {
+        id: String,
+        start_secs: u64,
+        end_secs: u64,
+    },
+
+    /// Sort the queue by votes, sent after the vote debounce delay
+    SortQueueByVotes,
+
+    /// Queue order was changed
+    QueueReordered,
+
+    /// Download progress of the currently playing song
+    BufferingProgress { percent: u8 },
+
+    /// Vote to skip the current song
+    SkipVote { nick: String },
+
+    /// Randomizes the order of the queue after the currently playing song
+    Shuffle,
+
+    /// Jumps to a position in the currently playing song
+    Seek { position_secs: u64 },
+
+    /// The player jumped to a position in the currently playing song
+    Seeked { position_secs: u64 },
+
+    /// Moves queued songs that lack `require_tag` or have `exclude_tag` out
+    /// of the queue, leaving the currently playing song alone
+    FilterQueue {
+        require_tag: Option<String>,
+        exclude_tag: Option<String>,
+    },
+
+    /// Moves all songs removed by [PlaybackAction::FilterQueue] back to the
+    /// end of the queue
+    ClearFilter,
+
+    /// A new song was sent to the decoder and is about to start playing
+    PlaybackStarted { song: Song },
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -72,6 +335,22 @@ struct PlaybackState {
     /// Whether we should start playing if queue empty and a new song is
     /// enqueued
     should_play: bool,
+
+    /// Votes cast on queued songs, keyed by song id
+    #[serde(default)]
+    song_votes: HashMap<String, SongVotes>,
+
+    /// Nicks that voted to skip the current song
+    #[serde(default)]
+    skip_votes: HashSet<String>,
+
+    /// Songs removed from the queue by a filter, in their original order
+    #[serde(default)]
+    filtered_songs: Vec<Song>,
+
+    #[serde(skip)]
+    /// Path that the state is persisted to
+    state_file: PathBuf,
 }
 
 impl Default for PlaybackState {
@@ -82,21 +361,30 @@ impl Default for PlaybackState {
             song_loaded: false,
             is_playing: false,
             should_play: true,
+            song_votes: HashMap::new(),
+            skip_votes: HashSet::new(),
+            filtered_songs: vec![],
+            state_file: PathBuf::new(),
         }
     }
 }
 
 impl PlaybackState {
-    async fn read_or_default() -> Self {
-        let res = tokio::fs::read(PLAYBACK_STATE_FILE).await;
+    async fn read_or_default(state_file: PathBuf) -> Self {
+        let res = tokio::fs::read(&state_file).await;
 
-        match res {
+        let state = match res {
             Ok(res) => serde_json::from_slice(&res).unwrap_or_default(),
             Err(e) => {
                 info!("Error while reading playback state: {:?}", e);
                 info!("Falling back to default state.");
                 PlaybackState::default()
             }
+        };
+
+        PlaybackState {
+            state_file,
+            ..state
         }
     }
 
@@ -105,8 +393,10 @@ impl PlaybackState {
 
         match json {
             Ok(json) => {
+                let state_file = self.state_file.clone();
+
                 tokio::spawn(async move {
-                    let res = tokio::fs::write(PLAYBACK_STATE_FILE, json).await;
+                    let res = persist::write_atomically(&state_file, json).await;
 
                     if let Err(e) = res {
                         error!("Error while writing state to disk: {:?}", e);
@@ -124,11 +414,29 @@ impl PlaybackState {
 pub struct Playback {
     bus: EventBus,
     state: PlaybackState,
+    config: PlaybackConfig,
+
+    /// When the queue was last sorted by votes
+    last_vote_sort: Option<Instant>,
+
+    /// Whether a sort by votes is already scheduled
+    vote_sort_scheduled: bool,
+
+    /// Progress of the currently playing song. Approximate, since it starts
+    /// counting when the song is requested from the decoder.
+    playback_progress: PlaybackProgress,
+
+    /// URLs or search terms that song info is currently being fetched for,
+    /// used to reject duplicate requests while a fetch is in progress
+    pending_fetches: HashSet<String>,
+
+    /// Number of songs in a row that failed to play
+    consecutive_failures: usize,
 }
 
 impl Playback {
-    pub async fn create(bus: EventBus) -> Playback {
-        let state = PlaybackState::read_or_default().await;
+    pub async fn create(bus: EventBus, state_file: PathBuf, config: PlaybackConfig) -> Playback {
+        let state = PlaybackState::read_or_default(state_file).await;
 
         debug!("Initial playback state:\n{:#?}", state);
 
@@ -136,7 +444,16 @@ impl Playback {
         let first_song = state.queued_songs.get(0).cloned();
         let should_play = state.should_play;
 
-        let mut playback = Playback { bus, state };
+        let mut playback = Playback {
+            bus,
+            state,
+            config,
+            last_vote_sort: None,
+            vote_sort_scheduled: false,
+            playback_progress: PlaybackProgress::default(),
+            pending_fetches: HashSet::new(),
+            consecutive_failures: 0,
+        };
 
         if should_play {
             if let Some(song) = first_song {
@@ -147,6 +464,18 @@ impl Playback {
         playback
     }
 
+    /// Responds to the user that queued `song`, or to the channel if the song
+    /// wasn't queued from IRC
+    fn reply_to_queuer(&self, song: &Song, msg: &str) {
+        match song.platform {
+            Platform::Irc => self.bus.send(Event::Irc(IrcAction::Reply {
+                nick: song.queued_by.clone(),
+                msg: msg.to_string(),
+            })),
+            Platform::Bot => self.irc_say(msg),
+        }
+    }
+
     /// Convenience method for sending irc messages
     fn irc_say(&self, msg: &str) {
         self.bus
@@ -166,10 +495,37 @@ impl Playback {
             / 60
     }
 
-    fn enqueue(&mut self, song: Song) {
+    /// Returns the limit of queued songs for the song's platform if adding
+    /// the song would exceed it
+    fn exceeded_platform_limit(&self, song: &Song) -> Option<usize> {
+        let limit = *self
+            .config
+            .max_songs_per_platform
+            .as_ref()?
+            .get(song.platform.name())?;
+
+        let queued = self
+            .state
+            .queued_songs
+            .iter()
+            .filter(|queued| queued.platform == song.platform)
+            .count();
+
+        (queued >= limit).then_some(limit)
+    }
+
+    fn enqueue(&mut self, mut song: Song) {
         if self.state.queued_songs.contains(&song) {
-            self.irc_say("Song already in queue!");
+            self.reply_to_queuer(&song, "Song already in queue!");
+        } else if let Some(limit) = self.exceeded_platform_limit(&song) {
+            let msg = format!(
+                "Too many songs queued from {}! Max is {limit} songs.",
+                song.platform.name()
+            );
+            self.reply_to_queuer(&song, &msg);
         } else {
+            song.enqueued_at = now_millis();
+
             let queue_was_empty = self.state.queued_songs.is_empty();
             let time_until_playback = self.queue_duration_mins();
             self.state.queued_songs.push(song.clone());
@@ -178,7 +534,7 @@ impl Playback {
                 "Added {} {} to the queue. Time until playback: {} min",
                 song.title, song.url, time_until_playback
             );
-            self.irc_say(&msg);
+            self.reply_to_queuer(&song, &msg);
 
             if !self.state.is_playing && self.state.should_play && queue_was_empty {
                 self.play_song(song)
@@ -188,6 +544,102 @@ impl Playback {
         }
     }
 
+    /// Inserts a song at `position`, clamped so that the currently playing
+    /// song is never displaced
+    fn enqueue_at(&mut self, mut song: Song, position: usize) {
+        if self.state.queued_songs.contains(&song) {
+            self.reply_to_queuer(&song, "Song already in queue!");
+        } else if let Some(limit) = self.exceeded_platform_limit(&song) {
+            let msg = format!(
+                "Too many songs queued from {}! Max is {limit} songs.",
+                song.platform.name()
+            );
+            self.reply_to_queuer(&song, &msg);
+        } else if self.state.queued_songs.is_empty() {
+            self.enqueue(song);
+        } else {
+            song.enqueued_at = now_millis();
+
+            let position = position.clamp(1, self.state.queued_songs.len());
+            self.state.queued_songs.insert(position, song.clone());
+
+            let msg = if position == 1 {
+                format!(
+                    "Inserted '{}' at position {position} (next after current)",
+                    song.title
+                )
+            } else {
+                format!("Inserted '{}' at position {position}", song.title)
+            };
+            self.reply_to_queuer(&song, &msg);
+
+            self.state.persist()
+        }
+    }
+
+    /// Inserts a song right after the current song, or appends it if the
+    /// queue is empty
+    fn insert_after_current(&mut self, song: Song) {
+        self.enqueue_at(song, 1);
+    }
+
+    /// Inserts a song before the last song in the queue. Appends it if the
+    /// queue only has the current song or is empty.
+    fn insert_before_last(&mut self, song: Song) {
+        let position = self.state.queued_songs.len().saturating_sub(1);
+        self.enqueue_at(song, position);
+    }
+
+    fn seek(&mut self, position_secs: u64) {
+        match self.state.queued_songs.first() {
+            // A duration of 0 means that it's unknown
+            Some(song) if song.duration > 0 && position_secs >= song.duration => {
+                self.irc_say(&format!(
+                    "Can't seek to {}, the song is only {} long",
+                    fmt_duration(position_secs),
+                    fmt_duration(song.duration)
+                ))
+            }
+            Some(_) if self.state.song_loaded => {
+                self.bus
+                    .send(Event::Symphonia(SymphoniaAction::Seek { position_secs }));
+            }
+            _ => self.irc_say("Nothing is playing right now!"),
+        }
+    }
+
+    fn seeked(&mut self, position_secs: u64) {
+        self.playback_progress
+            .seek(Duration::from_secs(position_secs));
+        self.irc_say(&format!("Jumped to {}", fmt_duration(position_secs)));
+    }
+
+    fn shuffle(&mut self) {
+        // Leave the currently playing song in place
+        let upcoming = self.state.queued_songs.get_mut(1..).unwrap_or_default();
+        upcoming.shuffle(&mut rand::thread_rng());
+        let num_shuffled = upcoming.len();
+
+        self.state.persist();
+        self.irc_say(&format!("Shuffled {num_shuffled} songs in the queue"));
+    }
+
+    fn enqueue_at_position(&mut self, song: Song, position: QueuePosition) {
+        match position {
+            QueuePosition::End => self.enqueue(song),
+            QueuePosition::At(position) => self.enqueue_at(song, position),
+
+            // Relative positions are resolved when the insert action is
+            // handled, against the queue as it is then
+            QueuePosition::AfterCurrent => self
+                .bus
+                .send(Event::Playback(PlaybackAction::InsertAfterCurrent { song })),
+            QueuePosition::BeforeLast => self
+                .bus
+                .send(Event::Playback(PlaybackAction::InsertBeforeLast { song })),
+        }
+    }
+
     fn list_queue(&self, offset: Option<usize>) {
         let fmt_song = |song: Option<&Song>| {
             song.map(|song| format!("{} (queued by {})", song.title, song.queued_by))
@@ -203,8 +655,7 @@ impl Playback {
         let msg = if is_empty {
             "Queue is empty!".to_string()
         } else if let Some(offset) = offset {
-            let song = fmt_song(self.state.queued_songs.get(offset));
-            format!("Song at position {offset}: {song}")
+            self.fmt_queue_entry(offset)
         } else {
             format!("Now playing: {np}, next up: {next}. Queue length: {len} ({duration_min} min)")
         };
@@ -212,13 +663,95 @@ impl Playback {
         self.irc_say(&msg);
     }
 
+    /// Estimated minutes until the song at `pos` starts playing
+    fn mins_until_pos(&self, pos: usize) -> u64 {
+        let total_secs = self
+            .state
+            .queued_songs
+            .iter()
+            .take(pos)
+            .map(|song| song.duration)
+            .sum::<u64>();
+
+        total_secs.saturating_sub(self.playback_progress.elapsed().as_secs()) / 60
+    }
+
+    fn now_playing(&self) {
+        let msg = match self.state.queued_songs.first() {
+            Some(song) => {
+                let elapsed = self
+                    .playback_progress
+                    .elapsed()
+                    .as_secs()
+                    .min(song.duration);
+
+                format!(
+                    "Now playing: {} [{}/{}] (queued by {})",
+                    song.title,
+                    fmt_duration(elapsed),
+                    fmt_duration(song.duration),
+                    song.queued_by
+                )
+            }
+            None => "Nothing is playing right now".to_string(),
+        };
+
+        self.irc_say(&msg);
+    }
+
+    /// Sends the playback status shown in the channel topic
+    fn update_topic(&self) {
+        let now_playing = match self.state.queued_songs.first() {
+            Some(song) if self.state.is_playing => Some(song.title.clone()),
+            _ => None,
+        };
+
+        self.bus.send(Event::Irc(IrcAction::PlaybackTopicInfo {
+            now_playing,
+            queue_len: self.state.queued_songs.len().saturating_sub(1),
+        }));
+    }
+
+    fn fmt_queue_entry(&self, pos: usize) -> String {
+        match self.state.queued_songs.get(pos) {
+            Some(song) if pos == 0 => format!(
+                "Now playing: {} by {} (queued by {})",
+                song.title, song.channel, song.queued_by
+            ),
+            Some(song) => format!(
+                "Song at position {pos}: {} by {} (queued by {}), playing in ~{} min",
+                song.title,
+                song.channel,
+                song.queued_by,
+                self.mins_until_pos(pos)
+            ),
+            None => format!("No song at position {pos} in the queue"),
+        }
+    }
+
+    fn list_queue_by_nick(&self, nick: String) {
+        let pos = self
+            .state
+            .queued_songs
+            .iter()
+            .rposition(|song| song.queued_by == nick);
+
+        match pos {
+            Some(pos) => self.irc_say(&self.fmt_queue_entry(pos)),
+            None => self.irc_say(&format!("No songs queued by {nick}")),
+        }
+    }
+
     fn rm_song_at_pos(&mut self, pos: usize) {
         let song = if pos == 0 {
             let song = self.state.queued_songs.get(0).cloned();
             self.next(true);
             song
         } else if pos < self.state.queued_songs.len() {
-            Some(self.state.queued_songs.remove(pos))
+            let song = self.state.queued_songs.remove(pos);
+            self.clear_votes_for(&song.id);
+            self.state.persist();
+            Some(song)
         } else {
             None
         };
@@ -229,6 +762,64 @@ impl Playback {
         }
     }
 
+    fn filter_queue(&mut self, require_tag: Option<String>, exclude_tag: Option<String>) {
+        let require_tag = require_tag.map(|tag| tag.to_lowercase());
+        let exclude_tag = exclude_tag.map(|tag| tag.to_lowercase());
+
+        let matches = |song: &Song| {
+            let required = match &require_tag {
+                Some(tag) => song.tags.contains(tag),
+                None => true,
+            };
+            let excluded = exclude_tag
+                .as_ref()
+                .is_some_and(|tag| song.tags.contains(tag));
+
+            required && !excluded
+        };
+
+        // Never filter out the currently playing song
+        let upcoming = if self.state.queued_songs.is_empty() {
+            vec![]
+        } else {
+            self.state.queued_songs.split_off(1)
+        };
+
+        let (kept, filtered): (Vec<Song>, Vec<Song>) = upcoming.into_iter().partition(matches);
+        let num_filtered = filtered.len();
+
+        self.state.queued_songs.extend(kept);
+        self.state.filtered_songs.extend(filtered);
+        self.state.persist();
+
+        self.irc_say(&format!(
+            "Filtered {num_filtered} songs out of the queue, {} songs are now hidden. Use !music clear-filter to restore them.",
+            self.state.filtered_songs.len()
+        ));
+    }
+
+    fn clear_filter(&mut self) {
+        let filtered = std::mem::take(&mut self.state.filtered_songs);
+
+        // Songs may have been requested again while they were filtered out
+        let mut queued_ids: HashSet<String> = self
+            .state
+            .queued_songs
+            .iter()
+            .map(|song| song.id.clone())
+            .collect();
+        let restored: Vec<Song> = filtered
+            .into_iter()
+            .filter(|song| queued_ids.insert(song.id.clone()))
+            .collect();
+        let num_restored = restored.len();
+
+        self.state.queued_songs.extend(restored);
+        self.state.persist();
+
+        self.irc_say(&format!("Restored {num_restored} songs to the queue"));
+    }
+
     fn rm_latest_song_by_nick(&mut self, nick: String) {
         let index = self
             .state
@@ -241,7 +832,10 @@ impl Playback {
             self.next(true);
             song
         } else if let Some(index) = index {
-            Some(self.state.queued_songs.remove(index))
+            let song = self.state.queued_songs.remove(index);
+            self.clear_votes_for(&song.id);
+            self.state.persist();
+            Some(song)
         } else {
             None
         };
@@ -252,20 +846,252 @@ impl Playback {
         }
     }
 
+    /// Returns the votes of a song, which are empty if nobody has voted
+    pub fn get_votes(&self, song_id: &str) -> SongVotes {
+        self.state
+            .song_votes
+            .get(song_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Forgets the votes of a song, called when it leaves the queue
+    fn clear_votes_for(&mut self, song_id: &str) {
+        self.state.song_votes.remove(song_id);
+    }
+
+    fn fmt_votes(votes: &SongVotes) -> String {
+        format!(
+            "{:+} ({} up, {} down)",
+            votes.score(),
+            votes.up.len(),
+            votes.down.len()
+        )
+    }
+
+    /// Records a vote, at most one per user and song. `song_id` may also be
+    /// the url of the song.
+    fn vote(&mut self, song_id: String, user: String, upvote: bool) {
+        let song = self
+            .state
+            .queued_songs
+            .iter()
+            .find(|song| song.id == song_id || song.url == song_id)
+            .cloned();
+
+        let Some(song) = song else {
+            self.irc_say(&format!("No song with id {song_id} in the queue"));
+            return;
+        };
+
+        let votes = self.state.song_votes.entry(song.id.clone()).or_default();
+
+        if upvote {
+            votes.down.remove(&user);
+            votes.up.insert(user);
+        } else {
+            votes.up.remove(&user);
+            votes.down.insert(user);
+        }
+
+        let votes = Self::fmt_votes(&self.get_votes(&song.id));
+        self.irc_say(&format!(
+            "Song '{}' now has a score of {votes}.",
+            song.title
+        ));
+
+        self.state.persist();
+        self.schedule_sort_by_votes();
+    }
+
+    fn list_votes(&self) {
+        let mut songs: Vec<(&Song, SongVotes)> = self
+            .state
+            .queued_songs
+            .iter()
+            .map(|song| (song, self.get_votes(&song.id)))
+            .filter(|(_, votes)| !votes.up.is_empty() || !votes.down.is_empty())
+            .collect();
+
+        if songs.is_empty() {
+            self.irc_say("No votes on queued songs");
+            return;
+        }
+
+        songs.sort_by_key(|(_, votes)| Reverse(votes.score()));
+
+        let msg = songs
+            .iter()
+            .take(MAX_LISTED_VOTES)
+            .map(|(song, votes)| format!("{}: {}", song.title, Self::fmt_votes(votes)))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        self.irc_say(&format!("Top voted songs: {msg}"));
+    }
+
+    fn refresh_duration(&self, id: String) {
+        let song = self.state.queued_songs.iter().find(|song| song.id == id);
+
+        match song {
+            Some(song) => self
+                .bus
+                .send(Event::Symphonia(SymphoniaAction::GetDuration {
+                    id,
+                    url: song.url.clone(),
+                })),
+            None => self.irc_say(&format!("No song with id {id} in the queue")),
+        }
+    }
+
+    fn set_duration(&mut self, id: String, duration: u64) {
+        let song = self
+            .state
+            .queued_songs
+            .iter_mut()
+            .find(|song| song.id == id);
+
+        let song = match song {
+            Some(song) => song,
+            None => return,
+        };
+
+        song.duration = duration;
+
+        let msg = format!(
+            "Updated duration of {} to {}",
+            song.title,
+            fmt_duration(duration)
+        );
+        self.irc_say(&msg);
+        self.state.persist();
+    }
+
+    fn trim(&mut self, id: String, start_secs: u64, end_secs: u64) {
+        let song = self
+            .state
+            .queued_songs
+            .iter_mut()
+            .find(|song| song.id == id);
+
+        let song = match song {
+            Some(song) => song,
+            None => {
+                self.irc_say(&format!("No song with id {id} in the queue"));
+                return;
+            }
+        };
+
+        if start_secs >= end_secs || end_secs > song.duration {
+            let msg = format!("Invalid trim, expected start < end <= {}", song.duration);
+            self.irc_say(&msg);
+            return;
+        }
+
+        song.trim = Some((start_secs, end_secs));
+
+        let msg = format!(
+            "Trimmed {} to {}-{}",
+            song.title,
+            fmt_duration(start_secs),
+            fmt_duration(end_secs)
+        );
+        self.irc_say(&msg);
+        self.state.persist();
+    }
+
+    /// Schedules a sort of the queue by votes, running at most once per
+    /// [VOTE_SORT_DEBOUNCE] to avoid constant reordering during active voting
+    fn schedule_sort_by_votes(&mut self) {
+        if self.vote_sort_scheduled {
+            return;
+        }
+
+        self.vote_sort_scheduled = true;
+
+        let delay = self
+            .last_vote_sort
+            .map(|t| VOTE_SORT_DEBOUNCE.saturating_sub(t.elapsed()))
+            .unwrap_or_default();
+        let bus = self.bus.clone();
+
+        tokio::spawn(async move {
+            sleep(delay).await;
+            bus.send(Event::Playback(PlaybackAction::SortQueueByVotes));
+        });
+    }
+
+    /// Sorts upcoming songs by vote score, keeping the currently playing song
+    /// in place. Ties are broken by enqueue order.
+    fn sort_queue_by_votes(&mut self) {
+        self.vote_sort_scheduled = false;
+        self.last_vote_sort = Some(Instant::now());
+
+        if self.state.queued_songs.len() > 1 {
+            let votes = &self.state.song_votes;
+
+            self.state.queued_songs[1..].sort_by_key(|song| {
+                let score = votes.get(&song.id).map(SongVotes::score).unwrap_or(0);
+                (Reverse(score), song.enqueued_at)
+            });
+        }
+
+        self.state.persist();
+        self.bus
+            .send(Event::Playback(PlaybackAction::QueueReordered));
+    }
+
     fn play_song(&mut self, song: Song) {
         self.state.is_playing = true;
         self.state.song_loaded = true;
+        self.playback_progress.restart();
+        self.state.skip_votes.clear();
 
         self.bus.send(Event::Symphonia(SymphoniaAction::PlayYtUrl {
-            url: song.url,
+            id: song.id.clone(),
+            url: song.url.clone(),
+            title: song.title.clone(),
+            trim: song.trim,
+            skip_segments: song.skip_segments.clone(),
         }));
+        self.bus
+            .send(Event::Playback(PlaybackAction::PlaybackStarted { song }));
 
         self.list_queue(None);
         self.state.persist();
     }
 
+    /// Skips the current song if it failed to play, or pauses playback if
+    /// too many songs have failed in a row
+    fn song_failed(&mut self, id: String, error: String) {
+        let Some(song) = self.state.queued_songs.first() else {
+            return;
+        };
+
+        // The failed song has already been skipped or removed
+        if song.id != id {
+            debug!("Ignoring playback error of song {id} that is no longer playing: {error}");
+            return;
+        }
+
+        let title = song.title.clone();
+        self.consecutive_failures += 1;
+
+        if self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+            self.irc_say(&format!(
+                "Error playing '{title}': {error}. {} songs in a row failed, pausing playback. Use !play to try again.",
+                self.consecutive_failures
+            ));
+            self.bus.send(Event::Playback(PlaybackAction::Pause));
+        } else {
+            self.irc_say(&format!("Error playing '{title}': {error}. Skipping."));
+            self.next(false);
+        }
+    }
+
     fn end_of_queue(&mut self) {
         self.state.is_playing = false;
+        self.playback_progress = PlaybackProgress::default();
 
         self.bus.send(Event::Symphonia(SymphoniaAction::Stop));
 
@@ -277,6 +1103,7 @@ impl Playback {
         if !self.state.queued_songs.is_empty() {
             // Move now playing song to played_songs
             let song = self.state.queued_songs.remove(0);
+            self.clear_votes_for(&song.id);
 
             if !remove_current {
                 self.state.played_songs.push(song);
@@ -295,26 +1122,101 @@ impl Playback {
         self.state.persist()
     }
 
-    fn prev(&mut self) {
-        let song = self.state.played_songs.pop();
+    /// Registers a vote from `nick` to skip the current song, and skips it
+    /// once enough nicks have voted
+    fn skip_vote(&mut self, nick: String) {
+        if !self.state.song_loaded {
+            self.irc_say("Nothing is playing right now!");
+            return;
+        }
 
-        if let Some(song) = song {
-            self.state.queued_songs.insert(0, song.clone());
-            self.play_song(song);
+        self.state.skip_votes.insert(nick.clone());
+
+        let votes = self.state.skip_votes.len();
+        let required = self.config.skip_votes_required.max(1);
+
+        if votes >= required {
+            self.irc_say(&format!(
+                "Skip vote from {nick} ({votes}/{required}). Skipping!"
+            ));
+            self.next(false);
         } else {
-            self.end_of_queue()
+            self.irc_say(&format!(
+                "Skip vote from {nick} ({votes}/{required}). Vote again or wait for playback."
+            ));
+            self.state.persist();
+        }
+    }
+
+    fn prev(&mut self) {
+        self.prev_n(1)
+    }
+
+    /// Moves the last `n` played songs back to the front of the queue and
+    /// plays the first of them
+    fn prev_n(&mut self, n: usize) {
+        let n = n.min(self.state.played_songs.len());
+        let split_at = self.state.played_songs.len() - n;
+        let songs = self.state.played_songs.split_off(split_at);
+
+        self.state.queued_songs.splice(0..0, songs);
+
+        match self.state.queued_songs.first().cloned() {
+            Some(song) if n > 0 => self.play_song(song),
+            _ => self.end_of_queue(),
         }
         self.state.persist()
     }
+
+    fn jump_to_played(&mut self, index: usize) {
+        let len = self.state.played_songs.len();
+
+        if index < len {
+            self.prev_n(len - index)
+        } else {
+            self.irc_say(&format!(
+                "No song at position {index} in the playback history"
+            ));
+        }
+    }
+}
+
+/// Fetches song info from YouTube, returning an error message if the song
+/// can't be queued
+async fn get_queueable_song(
+    url_or_search_terms: String,
+    queued_by: String,
+    config: &YoutubeConfig,
+) -> Result<Song, String> {
+    let song = get_yt_song_info(url_or_search_terms, queued_by, config).await;
+
+    match song {
+        Ok(song) if song.duration > MAX_SONG_DURATION.as_secs() => Err(format!(
+            "Requested song is too long! Max duration is {} minutes.",
+            MAX_SONG_DURATION.as_secs() / 60
+        )),
+        Ok(song) => Ok(song),
+        Err(e) => Err(format!("Error while getting song info: {e}")),
+    }
 }
 
-pub async fn init(bus: &EventBus) {
-    let playback = Arc::new(RwLock::new(Playback::create(bus.clone()).await));
+pub async fn init(bus: &EventBus, config: &Config) {
+    let state_file = config.state.playback_state_path();
+    let playback = Playback::create(bus.clone(), state_file, config.playback.clone()).await;
+    let playback = Arc::new(RwLock::new(playback));
+
+    handle_incoming_event_loop(bus.clone(), config.youtube.clone(), playback.clone());
 
-    handle_incoming_event_loop(bus.clone(), playback);
+    if let Some(interval) = config.state.heartbeat_interval() {
+        persist::start_heartbeat(playback, interval, |playback| playback.state.persist());
+    }
 }
 
-fn handle_incoming_event_loop(bus: EventBus, playback: Arc<RwLock<Playback>>) {
+fn handle_incoming_event_loop(
+    bus: EventBus,
+    youtube_config: YoutubeConfig,
+    playback: Arc<RwLock<Playback>>,
+) {
     tokio::spawn(async move {
         let mut bus_rx = bus.subscribe();
 
@@ -323,28 +1225,74 @@ fn handle_incoming_event_loop(bus: EventBus, playback: Arc<RwLock<Playback>>) {
 
             if let Event::Playback(action) = event {
                 let playback = playback.clone();
+                let youtube_config = youtube_config.clone();
                 tokio::spawn(async move {
-                    handle_incoming_event(action, playback).await;
+                    handle_incoming_event(action, &youtube_config, playback).await;
                 });
             }
         }
     });
 }
 
-async fn handle_incoming_event(action: PlaybackAction, playback: Arc<RwLock<Playback>>) {
-    let mut playback = playback.write().await;
+async fn handle_incoming_event(
+    action: PlaybackAction,
+    youtube_config: &YoutubeConfig,
+    playback_rwlock: Arc<RwLock<Playback>>,
+) {
+    let mut playback = playback_rwlock.write().await;
     match action {
         PlaybackAction::Enqueue { song } => playback.enqueue(song),
+        PlaybackAction::InsertAfterCurrent { song } => playback.insert_after_current(song),
+        PlaybackAction::InsertBeforeLast { song } => playback.insert_before_last(song),
+        PlaybackAction::EnqueueUrl {
+            url_or_search_terms,
+            queued_by,
+            platform,
+            position,
+        } => {
+            if !playback.pending_fetches.insert(url_or_search_terms.clone()) {
+                playback.bus.send(Event::Irc(IrcAction::Reply {
+                    nick: queued_by,
+                    msg: "Already fetching that song, hold on!".to_string(),
+                }));
+                return;
+            }
+
+            // Don't hold onto the lock while fetching song info
+            drop(playback);
+
+            let song = get_queueable_song(
+                url_or_search_terms.clone(),
+                queued_by.clone(),
+                youtube_config,
+            )
+            .await;
+
+            playback = playback_rwlock.write().await;
+            playback.pending_fetches.remove(&url_or_search_terms);
+
+            match song {
+                Ok(song) => playback.enqueue_at_position(Song { platform, ..song }, position),
+                Err(msg) => playback.bus.send(Event::Irc(IrcAction::Reply {
+                    nick: queued_by,
+                    msg,
+                })),
+            }
+        }
         PlaybackAction::ListQueue { offset } => {
             playback.list_queue(offset);
         }
+        PlaybackAction::ListQueueByNick { nick } => playback.list_queue_by_nick(nick),
+        PlaybackAction::NowPlaying => playback.now_playing(),
         PlaybackAction::RmSongByPos { pos } => playback.rm_song_at_pos(pos),
         PlaybackAction::RmSongByNick { nick } => playback.rm_latest_song_by_nick(nick),
         PlaybackAction::Play => {
             playback.state.should_play = true;
+            playback.consecutive_failures = 0;
 
             if playback.state.song_loaded {
                 playback.state.is_playing = true;
+                playback.playback_progress.resume();
                 playback.bus.send(Event::Symphonia(SymphoniaAction::Resume));
             } else {
                 // Play next song if it exists
@@ -359,12 +1307,14 @@ async fn handle_incoming_event(action: PlaybackAction, playback: Arc<RwLock<Play
         PlaybackAction::Pause => {
             playback.state.is_playing = false;
             playback.state.should_play = false;
+            playback.playback_progress.pause();
 
             playback.bus.send(Event::Symphonia(SymphoniaAction::Pause));
 
             playback.state.persist();
         }
         PlaybackAction::EndOfSong => {
+            playback.consecutive_failures = 0;
             playback.state.is_playing = false;
             playback.state.song_loaded = false;
             playback.next(false);
@@ -372,8 +1322,383 @@ async fn handle_incoming_event(action: PlaybackAction, playback: Arc<RwLock<Play
         PlaybackAction::Next => {
             playback.next(false);
         }
+        PlaybackAction::SongFailed { id, error } => playback.song_failed(id, error),
+        PlaybackAction::SkipVote { nick } => playback.skip_vote(nick),
+        PlaybackAction::Shuffle => playback.shuffle(),
+        PlaybackAction::Seek { position_secs } => playback.seek(position_secs),
+        PlaybackAction::Seeked { position_secs } => playback.seeked(position_secs),
+        PlaybackAction::FilterQueue {
+            require_tag,
+            exclude_tag,
+        } => playback.filter_queue(require_tag, exclude_tag),
+        PlaybackAction::ClearFilter => playback.clear_filter(),
         PlaybackAction::Prev => {
             playback.prev();
         }
+        PlaybackAction::PrevN { n } => playback.prev_n(n.get()),
+        PlaybackAction::JumpToPlayed { index } => playback.jump_to_played(index),
+        PlaybackAction::Upvote { song_id, user } => playback.vote(song_id, user, true),
+        PlaybackAction::Downvote { song_id, user } => playback.vote(song_id, user, false),
+        PlaybackAction::ListVotes => playback.list_votes(),
+        PlaybackAction::RefreshDuration { id } => playback.refresh_duration(id),
+        PlaybackAction::SetDuration { id, duration } => playback.set_duration(id, duration),
+        PlaybackAction::Trim {
+            id,
+            start_secs,
+            end_secs,
+        } => playback.trim(id, start_secs, end_secs),
+        PlaybackAction::SortQueueByVotes => playback.sort_queue_by_votes(),
+        PlaybackAction::QueueReordered => {}
+        PlaybackAction::BufferingProgress { percent } => debug!("Buffering {percent}%"),
+        PlaybackAction::PlaybackStarted { song } => info!("Started playing {}", song.title),
+    }
+
+    playback.update_topic();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str) -> Song {
+        Song {
+            id: id.to_string(),
+            url: format!("https://www.youtube.com/watch?v={id}"),
+            title: id.to_string(),
+            channel: "channel".to_string(),
+            duration: 60,
+            queued_by: "nick".to_string(),
+            enqueued_at: 0,
+            trim: None,
+            skip_segments: vec![],
+            tags: vec![],
+            platform: Platform::Irc,
+        }
+    }
+
+    fn ids(playback: &Playback) -> Vec<&str> {
+        playback
+            .state
+            .queued_songs
+            .iter()
+            .map(|song| song.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn songbook_song_converts_with_defaults() {
+        let song = Song::from(SongbookSong {
+            id: "helan-gar".to_string(),
+            url: Some("https://www.youtube.com/watch?v=abc".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(song.id, "helan-gar");
+        assert_eq!(song.url, "https://www.youtube.com/watch?v=abc");
+        assert_eq!(song.title, "helan-gar");
+        assert_eq!(song.channel, "Songbook");
+        assert_eq!(song.queued_by, "admin");
+        assert_eq!(song.duration, 0);
+        assert_eq!(song.platform, Platform::Bot);
+    }
+
+    #[test]
+    fn songbook_song_converts_with_its_details() {
+        let song = Song::from(SongbookSong {
+            id: "helan-gar".to_string(),
+            url: None,
+            title: Some("Helan går".to_string()),
+            book: Some("Sångboken".to_string()),
+            queued_by: Some("nick".to_string()),
+            tags: vec!["Dryckesvisor".to_string()],
+            ..Default::default()
+        });
+
+        assert_eq!(song.url, "");
+        assert_eq!(song.title, "Helan går");
+        assert_eq!(song.channel, "Sångboken");
+        assert_eq!(song.queued_by, "nick");
+        assert_eq!(song.tags, ["dryckesvisor"]);
+    }
+
+    /// Playback with `queue_ids` queued that doesn't start playing new songs
+    fn playback_with_queue(bus: EventBus, queue_ids: &[&str]) -> Playback {
+        Playback {
+            bus,
+            state: PlaybackState {
+                queued_songs: queue_ids.iter().map(|id| song(id)).collect(),
+                should_play: false,
+                state_file: std::env::temp_dir().join("irc-sitz-test-playback_state.json"),
+                ..Default::default()
+            },
+            config: PlaybackConfig {
+                max_songs_per_platform: None,
+                skip_votes_required: 3,
+                crossfade_duration_ms: 0,
+            },
+            last_vote_sort: None,
+            vote_sort_scheduled: false,
+            playback_progress: PlaybackProgress::default(),
+            pending_fetches: HashSet::new(),
+            consecutive_failures: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn shuffle_is_a_no_op_for_empty_and_single_song_queues() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut playback = playback_with_queue(bus, &[]);
+        playback.shuffle();
+        assert!(ids(&playback).is_empty());
+
+        playback.state.queued_songs.push(song("current"));
+        playback.shuffle();
+        assert_eq!(ids(&playback), ["current"]);
+
+        let events = captured.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|event| matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg == "Shuffled 0 songs in the queue"
+        )));
+    }
+
+    #[tokio::test]
+    async fn shuffle_keeps_the_current_song_first() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "a", "b", "c"]);
+
+        playback.shuffle();
+
+        let mut shuffled = ids(&playback);
+        assert_eq!(shuffled[0], "current");
+        shuffled.sort();
+        assert_eq!(shuffled, ["a", "b", "c", "current"]);
+    }
+
+    #[tokio::test]
+    async fn insert_into_empty_queue_appends() {
+        let mut playback = playback_with_queue(EventBus::new(), &[]);
+        playback.insert_after_current(song("a"));
+        assert_eq!(ids(&playback), ["a"]);
+
+        let mut playback = playback_with_queue(EventBus::new(), &[]);
+        playback.insert_before_last(song("a"));
+        assert_eq!(ids(&playback), ["a"]);
+    }
+
+    #[tokio::test]
+    async fn insert_with_only_current_song_appends() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current"]);
+        playback.insert_after_current(song("a"));
+        assert_eq!(ids(&playback), ["current", "a"]);
+
+        let mut playback = playback_with_queue(EventBus::new(), &["current"]);
+        playback.insert_before_last(song("a"));
+        assert_eq!(ids(&playback), ["current", "a"]);
+    }
+
+    #[tokio::test]
+    async fn insert_after_current_keeps_current_song_first() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "b", "c"]);
+
+        playback.insert_after_current(song("a"));
+
+        assert_eq!(ids(&playback), ["current", "a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn insert_before_last_keeps_last_song_last() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "b", "c"]);
+
+        playback.insert_before_last(song("a"));
+
+        assert_eq!(ids(&playback), ["current", "b", "a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn insert_rejects_duplicates() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "b"]);
+
+        playback.insert_after_current(song("b"));
+        playback.insert_before_last(song("current"));
+
+        assert_eq!(ids(&playback), ["current", "b"]);
+    }
+
+    #[tokio::test]
+    async fn relative_positions_are_inserted_through_actions() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut playback = playback_with_queue(bus, &["current"]);
+
+        playback.enqueue_at_position(song("a"), QueuePosition::AfterCurrent);
+        playback.enqueue_at_position(song("b"), QueuePosition::BeforeLast);
+
+        assert!(matches!(
+            captured.lock().unwrap().as_slice(),
+            [
+                Event::Playback(PlaybackAction::InsertAfterCurrent { song: a }),
+                Event::Playback(PlaybackAction::InsertBeforeLast { song: b }),
+            ] if a.id == "a" && b.id == "b"
+        ));
+    }
+
+    fn last_irc_msg(captured: &std::sync::Mutex<Vec<Event>>) -> Option<String> {
+        captured
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find_map(|event| match event {
+                Event::Irc(IrcAction::SendMsg(msg)) => Some(msg.clone()),
+                _ => None,
+            })
+    }
+
+    #[tokio::test]
+    async fn votes_are_counted_once_per_user() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut playback = playback_with_queue(bus, &["current", "a"]);
+
+        playback.vote("a".into(), "nick1".into(), true);
+        playback.vote("a".into(), "nick1".into(), true);
+        playback.vote("a".into(), "nick2".into(), true);
+        playback.vote("a".into(), "nick3".into(), false);
+
+        assert_eq!(playback.get_votes("a").score(), 1);
+        assert_eq!(
+            last_irc_msg(&captured).as_deref(),
+            Some("Song 'a' now has a score of +1 (2 up, 1 down).")
+        );
+    }
+
+    #[tokio::test]
+    async fn changing_a_vote_replaces_it() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "a"]);
+
+        playback.vote("a".into(), "nick1".into(), true);
+        playback.vote(
+            "https://www.youtube.com/watch?v=a".into(),
+            "nick1".into(),
+            false,
+        );
+
+        let votes = playback.get_votes("a");
+        assert!(votes.up.is_empty());
+        assert_eq!(votes.down.len(), 1);
+        assert_eq!(votes.score(), -1);
+    }
+
+    #[tokio::test]
+    async fn sort_by_votes_orders_upcoming_songs_by_score() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut playback = playback_with_queue(bus, &["current", "a", "b", "c", "d"]);
+        for (enqueued_at, song) in playback.state.queued_songs.iter_mut().enumerate() {
+            song.enqueued_at = enqueued_at as u64;
+        }
+
+        // The current song stays first no matter its score
+        playback.vote("current".into(), "nick1".into(), false);
+        playback.vote("b".into(), "nick1".into(), false);
+        playback.vote("c".into(), "nick1".into(), true);
+        playback.vote("c".into(), "nick2".into(), true);
+        playback.vote("d".into(), "nick1".into(), true);
+        playback.sort_queue_by_votes();
+
+        assert_eq!(ids(&playback), ["current", "c", "d", "a", "b"]);
+        assert!(matches!(
+            captured.lock().unwrap().last(),
+            Some(Event::Playback(PlaybackAction::QueueReordered))
+        ));
+    }
+
+    #[tokio::test]
+    async fn sort_by_votes_breaks_ties_by_enqueue_order() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current", "a", "b", "c"]);
+        for (song, enqueued_at) in playback.state.queued_songs.iter_mut().zip([0, 3, 1, 2]) {
+            song.enqueued_at = enqueued_at;
+        }
+
+        playback.sort_queue_by_votes();
+
+        assert_eq!(ids(&playback), ["current", "b", "c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn three_distinct_skip_votes_skip_the_song() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut playback = playback_with_queue(bus, &["current", "next"]);
+        playback.state.song_loaded = true;
+
+        playback.skip_vote("nick1".into());
+        playback.skip_vote("nick1".into());
+        playback.skip_vote("nick2".into());
+        assert_eq!(ids(&playback), ["current", "next"]);
+        assert_eq!(
+            last_irc_msg(&captured).as_deref(),
+            Some("Skip vote from nick2 (2/3). Vote again or wait for playback.")
+        );
+
+        playback.skip_vote("nick3".into());
+
+        assert_eq!(ids(&playback), ["next"]);
+        assert_eq!(playback.state.played_songs[0].id, "current");
+        assert!(playback.state.skip_votes.is_empty());
+        assert!(captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Symphonia(SymphoniaAction::PlayYtUrl { id, .. }) if id == "next"
+        )));
+    }
+
+    #[tokio::test]
+    async fn skip_vote_needs_a_playing_song() {
+        let mut playback = playback_with_queue(EventBus::new(), &["current"]);
+
+        playback.skip_vote("nick1".into());
+
+        assert!(playback.state.skip_votes.is_empty());
+    }
+
+    fn tagged_song(id: &str, tags: &[&str]) -> Song {
+        Song {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..song(id)
+        }
+    }
+
+    #[tokio::test]
+    async fn filter_and_clear_filter_round_trip() {
+        let mut playback = playback_with_queue(EventBus::new(), &[]);
+        playback.state.queued_songs = vec![
+            tagged_song("current", &["dinner"]),
+            tagged_song("a", &["party"]),
+            tagged_song("b", &["dinner"]),
+            tagged_song("c", &["party", "slow"]),
+        ];
+
+        playback.filter_queue(Some("Party".into()), None);
+        assert_eq!(ids(&playback), ["current", "a", "c"]);
+
+        playback.filter_queue(None, Some("slow".into()));
+        assert_eq!(ids(&playback), ["current", "a"]);
+
+        playback.clear_filter();
+        assert_eq!(ids(&playback), ["current", "a", "b", "c"]);
+        assert!(playback.state.filtered_songs.is_empty());
+    }
+
+    #[tokio::test]
+    async fn clear_filter_skips_songs_queued_again() {
+        let mut playback = playback_with_queue(EventBus::new(), &[]);
+        playback.state.queued_songs = vec![
+            tagged_song("current", &[]),
+            tagged_song("a", &["party"]),
+            tagged_song("b", &[]),
+        ];
+
+        playback.filter_queue(Some("party".into()), None);
+        playback.state.queued_songs.push(tagged_song("b", &[]));
+        playback.clear_filter();
+
+        assert_eq!(ids(&playback), ["current", "a", "b"]);
     }
 }
@@ -1,11 +1,45 @@
-use std::fmt::{Display, Formatter};
+use std::{
+    fmt::{Display, Formatter},
+    time::Duration,
+};
 
 use anyhow::{anyhow, Context, Result};
+use itertools::Itertools;
+use reqwest::Url;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
+use tokio::task::{JoinError, JoinSet};
 
 use crate::config::Config;
 
+const LINK_VALIDATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Errors from fetching song info, with messages meant for the requester
+#[derive(Debug, thiserror::Error)]
+pub enum SongbookError {
+    #[error("{0}")]
+    InvalidUrl(anyhow::Error),
+
+    #[error("That song URL doesn't exist in the songbook")]
+    NotFound(String),
+
+    #[error("Couldn't reach the songbook, try again later")]
+    NetworkError(#[source] reqwest::Error),
+
+    #[error("Songbook returned unexpected data")]
+    ParseError(#[source] reqwest::Error),
+}
+
+impl From<reqwest::Error> for SongbookError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            SongbookError::ParseError(e)
+        } else {
+            SongbookError::NetworkError(e)
+        }
+    }
+}
+
 #[derive(Clone, Default, Debug, Deserialize, Serialize)]
 pub struct SongbookSong {
     pub id: String,
@@ -13,6 +47,27 @@ pub struct SongbookSong {
     pub title: Option<String>,
     pub book: Option<String>,
     pub queued_by: Option<String>,
+
+    /// Link to the song's page in the songbook app
+    #[serde(default)]
+    pub page_url: Option<String>,
+
+    /// Categories of the song in the songbook, e.g. "Dryckesvisor"
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl SongbookSong {
+    /// Link to show to users, preferring the songbook page over `url`
+    pub fn link(&self) -> Option<&String> {
+        self.page_url.as_ref().or(self.url.as_ref())
+    }
+
+    /// Case insensitively checks whether the song has the given tag
+    pub fn has_tag(&self, tag: &str) -> bool {
+        let tag = tag.to_lowercase();
+        self.tags.iter().any(|t| t.to_lowercase() == tag)
+    }
 }
 
 impl PartialEq for SongbookSong {
@@ -34,32 +89,149 @@ impl Display for SongbookSong {
     }
 }
 
-pub async fn get_song_info(url: &str, config: &Config, queued_by: &str) -> Result<SongbookSong> {
-    let url_matches = config.songbook.songbook_re.captures(url).with_context(|| {
+/// Returns the host of `url` if it is in `allowed_songbook_domains`
+fn allowed_host(url: &Url, config: &Config) -> Option<String> {
+    let host = url.host_str()?.to_lowercase();
+
+    // Parse configured domains as URLs to normalize e.g. IDNs into punycode
+    let is_allowed = config
+        .songbook
+        .allowed_songbook_domains
+        .iter()
+        .filter_map(|domain| Url::parse(&format!("https://{domain}")).ok())
+        .any(|domain| domain.host_str().map(str::to_lowercase) == Some(host.clone()));
+
+    is_allowed.then_some(host)
+}
+
+/// Checks whether `url` looks like a songbook URL that can be requested
+pub fn is_songbook_url(url: &str, config: &Config) -> bool {
+    if config.songbook.allowed_songbook_domains.is_empty() {
+        return config.songbook.songbook_re.is_match(url);
+    }
+
+    Url::parse(url)
+        .ok()
+        .and_then(|url| allowed_host(&url, config))
+        .is_some()
+}
+
+/// Extracts the song ID from a songbook URL, validating the host against
+/// `allowed_songbook_domains` if any are configured
+fn song_id_from_url(url: &str, config: &Config) -> Result<String> {
+    let url_mismatch = || {
         format!(
             "URL mismatch, try pasting a URL from {}",
             config.songbook.songbook_url
         )
-    })?;
-    let id = url_matches
-        .get(2)
-        .map(|id| id.as_str().to_string())
-        .context("No ID found in URL")?;
+    };
 
-    let result = reqwest::get(url)
-        .await
-        .with_context(|| format!("Request to {url} failed"))?
-        .error_for_status();
+    if config.songbook.allowed_songbook_domains.is_empty() {
+        let url_matches = config
+            .songbook
+            .songbook_re
+            .captures(url)
+            .with_context(url_mismatch)?;
+
+        return url_matches
+            .get(2)
+            .map(|id| id.as_str().to_string())
+            .context("No ID found in URL");
+    }
+
+    let parsed_url = Url::parse(url).with_context(url_mismatch)?;
+    let host = parsed_url.host_str().unwrap_or_default();
+
+    if allowed_host(&parsed_url, config).is_none() {
+        return Err(anyhow!(
+            "Songbook domain {host} is not allowed, try pasting a URL from {}",
+            config.songbook.songbook_url
+        ));
+    }
+
+    // Prefer the ID captured by songbook_re, fall back to the last path
+    // segment for other allowed songbooks
+    let id = match config.songbook.songbook_re.captures(url) {
+        Some(url_matches) => url_matches.get(2).map(|id| id.as_str().to_string()),
+        None => parsed_url
+            .path_segments()
+            .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+            .map(|id| id.to_string()),
+    };
+
+    id.context("No ID found in URL")
+}
+
+/// Words ignored when comparing titles for duplicates
+const DEDUP_IGNORED_WORDS: [&str; 3] = ["the", "a", "an"];
+
+/// Normalizes a song title for near-duplicate detection by lowercasing it and
+/// stripping punctuation and articles
+pub fn normalize_title_for_dedup(title: &str) -> String {
+    let title: String = title
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect();
+
+    title
+        .split_whitespace()
+        .filter(|word| !DEDUP_IGNORED_WORDS.contains(word))
+        .join(" ")
+}
+
+/// Levenshtein distance between two strings, counted in characters
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev_row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution_cost = if a_char == *b_char { 0 } else { 1 };
+
+            row[j + 1] = (prev_row[j] + substitution_cost)
+                .min(prev_row[j + 1] + 1)
+                .min(row[j] + 1);
+        }
+
+        prev_row = row;
+    }
+
+    prev_row[b.len()]
+}
+
+/// Checks whether two normalized titles are near-duplicates, allowing an
+/// edit distance of up to `max_ratio` times the length of the longer title
+/// so that short titles need to match more closely than long ones
+pub fn is_similar_title(a: &str, b: &str, max_ratio: f64) -> bool {
+    let len = a.chars().count().max(b.chars().count());
+    edit_distance(a, b) as f64 <= max_ratio * len as f64
+}
+
+pub async fn get_song_info(
+    url: &str,
+    config: &Config,
+    queued_by: &str,
+) -> Result<SongbookSong, SongbookError> {
+    let id = song_id_from_url(url, config).map_err(SongbookError::InvalidUrl)?;
+
+    let result = reqwest::get(url).await?.error_for_status();
 
     let response = match result {
         Ok(response) => response,
-        Err(e) => return Err(anyhow!("Failed to get songbook song info: {}", e)),
+        Err(e) if e.status() == Some(reqwest::StatusCode::NOT_FOUND) => {
+            return Err(SongbookError::NotFound(url.to_string()))
+        }
+        Err(e) => return Err(e.into()),
     };
 
     let html = response.text().await?;
     let document = Html::parse_document(&html);
     let title_selector = Selector::parse("h1").unwrap();
     let book_selector = Selector::parse("[class^=SongTags__Wrapper] > *:last-child").unwrap();
+    let tag_selector = Selector::parse("[class^=SongTags__Wrapper] > *").unwrap();
 
     let title = document
         .select(&title_selector)
@@ -73,11 +245,161 @@ pub async fn get_song_info(url: &str, config: &Config, queued_by: &str) -> Resul
         .and_then(|element| element.text().next())
         .map(|text| text.to_string());
 
+    let tags = document
+        .select(&tag_selector)
+        .filter_map(|element| element.text().next())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+        .collect();
+
+    // Songs from the configured songbook link to its canonical page, songs
+    // from other allowed songbooks link to the requested URL as is
+    let page_url = if config.songbook.songbook_re.is_match(url) {
+        format!("{}/{}", config.songbook.songbook_url, id)
+    } else {
+        url.to_string()
+    };
+
     Ok(SongbookSong {
         url: Some(url.to_string()),
         id,
         title,
         book,
         queued_by: Some(queued_by.to_string()),
+        page_url: Some(page_url),
+        tags,
     })
 }
+
+/// Returns the URLs of all songs listed on the songbook page for `tag`
+pub async fn search_by_tag(tag: &str, config: &Config) -> Result<Vec<String>> {
+    let base_url = Url::parse(&config.songbook.songbook_url)
+        .with_context(|| format!("Invalid songbook URL {}", config.songbook.songbook_url))?;
+
+    let mut search_url = base_url.clone();
+    search_url.query_pairs_mut().append_pair("tag", tag);
+
+    let result = reqwest::get(search_url.as_str())
+        .await
+        .with_context(|| format!("Request to {search_url} failed"))?
+        .error_for_status();
+
+    let response = match result {
+        Ok(response) => response,
+        Err(e) => return Err(anyhow!("Failed to search songbook by tag: {}", e)),
+    };
+
+    let html = response.text().await?;
+    let document = Html::parse_document(&html);
+    let link_selector = Selector::parse("a[href]").unwrap();
+
+    let urls = document
+        .select(&link_selector)
+        .filter_map(|element| element.value().attr("href"))
+        .filter_map(|href| base_url.join(href).ok())
+        // Skip links back to the listing pages
+        .filter(|url| url.query().is_none() && url.path() != "/")
+        .map(|url| url.to_string())
+        .filter(|url| config.songbook.songbook_re.is_match(url))
+        .unique()
+        .collect();
+
+    Ok(urls)
+}
+
+async fn check_link(client: &reqwest::Client, url: &str) -> Result<()> {
+    client
+        .head(url)
+        .timeout(LINK_VALIDATION_TIMEOUT)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+/// Sends HEAD requests to the URLs of `songs` and returns the IDs of songs
+/// whose links are broken along with the reason
+pub async fn validate_links(
+    songs: &[SongbookSong],
+    config: &Config,
+) -> Vec<(String, anyhow::Error)> {
+    let client = reqwest::Client::new();
+    let concurrency = config.songbook.link_validation_concurrency.max(1);
+
+    let mut broken = vec![];
+    let mut join_set = JoinSet::new();
+
+    let mut collect = |result: Result<(String, Result<()>), JoinError>| match result {
+        Ok((id, Err(e))) => broken.push((id, e)),
+        Ok((_, Ok(()))) => {}
+        Err(e) => error!("Link validation task failed: {:?}", e),
+    };
+
+    for song in songs {
+        let Some(url) = song.url.clone() else {
+            continue;
+        };
+
+        if join_set.len() >= concurrency {
+            if let Some(result) = join_set.join_next().await {
+                collect(result);
+            }
+        }
+
+        let client = client.clone();
+        let id = song.id.clone();
+
+        join_set.spawn(async move { (id, check_link(&client, &url).await) });
+    }
+
+    while let Some(result) = join_set.join_next().await {
+        collect(result);
+    }
+
+    broken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_characters() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("abc", ""), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("helan går", "halvan går"), 2);
+        assert_eq!(edit_distance("går", "gar"), 1);
+    }
+
+    #[test]
+    fn normalize_title_for_dedup_ignores_case_punctuation_and_articles() {
+        assert_eq!(normalize_title_for_dedup("The  Helan, går!"), "helan går");
+        assert_eq!(normalize_title_for_dedup("A Song of an Ode"), "song of ode");
+    }
+
+    #[test]
+    fn is_similar_title_is_relative_to_title_length() {
+        let similar = |a: &str, b: &str| {
+            is_similar_title(
+                &normalize_title_for_dedup(a),
+                &normalize_title_for_dedup(b),
+                0.15,
+            )
+        };
+
+        assert!(similar("Helan går", "helan går!"));
+        assert!(similar("Helan går", "Helan gar"));
+        assert!(!similar("Helan går", "Halvan går"));
+        assert!(similar("Fjärran han dröjer", "Fjaran han dröjer"));
+        assert!(!similar("Rattataa", "Nu är det nu"));
+    }
+
+    #[test]
+    fn is_similar_title_with_zero_ratio_requires_identical_titles() {
+        assert!(is_similar_title("helan går", "helan går", 0.0));
+        assert!(!is_similar_title("helan går", "helan gar", 0.0));
+    }
+}
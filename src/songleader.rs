@@ -1,36 +1,44 @@
 use crate::{
-    config::Config,
+    config::{Config, SongleaderConfig},
     event::{Event, EventBus},
-    irc::IrcAction,
-    playback::PlaybackAction,
+    irc::{self, IrcAction},
+    localization, persist,
+    playback::{self, PlaybackAction},
     songbook::{self, SongbookSong},
     sources::espeak::{Priority, TextToSpeechAction},
 };
 use anyhow::{anyhow, Result};
-use rand::Rng;
+use chrono::{Local, NaiveTime};
+use rand::{seq::SliceRandom, Rng};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
+    panic::AssertUnwindSafe,
+    path::PathBuf,
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
     sync::RwLock,
     time::{sleep, Instant},
 };
+use tokio_util::sync::CancellationToken;
 
-const SONGLEADER_STATE_FILE: &str = "songleader_state.json";
-const NUM_TEMPO_NICKS: usize = 3;
-const NUM_BINGO_NICKS: usize = 3;
-const ANTI_FLOOD_DELAY: Duration = Duration::from_millis(1200);
 const SECOND: Duration = Duration::from_secs(1);
-const TEMPO_DEADLINE_REDUCTION: Duration = Duration::from_secs(60);
-const TEMPO_DEADLINE: Duration = Duration::from_secs(420);
+const TEMPO_HISTORY_LEN: usize = 20;
+const DEFAULT_TEMPO_HISTORY_COUNT: usize = 5;
+
+/// Longest timeout that can be set with [SongleaderAction::SetModeTimeout]
+pub const MAX_MODE_TIMEOUT_SECS: u64 = 24 * 60 * 60;
+
+const REQUESTS_CLOSED_MSG: &str = "Song requests are not open right now";
+
 const HELP_TEXT: &str = r#"
 ===================================================================
 Useful commands:
 Add a YouTube URL to the music queue:     !p https://youtu.be/dQw4w9WgXcQ
 Remove most recently queued music by you: !rm
+Vote to skip the current music:           !skip
 Request a song you want to sing:          !request songbook-url
 List current requests:                    !ls
 To say stuff, use:                        !speak hello world
@@ -43,26 +51,108 @@ pub enum SongleaderAction {
     /// Requests a song to be sung from an URL
     RequestSongUrl { url: String, queued_by: String },
 
+    /// Requests a random song with the given tag from the songbook
+    RequestSongByTag { tag: String, queued_by: String },
+
     /// Requests a song to be sung by name
     RequestSong { song: SongbookSong },
 
+    /// Requests many songs at once, responding with a single summary
+    BulkRequestSongs { songs: Vec<SongbookSong> },
+
     /// Removes a song by ID
     RmSongById { id: String },
 
     /// Removes latest song queued by nick
     RmSongByNick { nick: String },
 
+    /// Moves a requested song to the front of the first songs so that it is
+    /// sung next
+    PrioritizeSong { id: String },
+
+    /// Moves a prioritized song back to the requests
+    DeprioritizeSong { id: String },
+
+    /// Moves the first request to the front of the first songs, which
+    /// [SongleaderState::pop_next_song] takes songs from before any requests
+    PromoteNextRequest,
+
+    /// Moves the first of the first songs to the front of the requests, so
+    /// that it is sung after the remaining first songs
+    DemoteFirstSong,
+
+    /// Swaps the positions of two requested songs
+    SwapRequests { id_a: String, id_b: String },
+
+    /// Rearranges all requested songs to match the given order of song ids
+    ReorderRequests { new_order: Vec<String> },
+
+    /// Moves requested songs to the first songs in the given order
+    PinSongs { ids: Vec<String> },
+
+    /// Moves pinned songs back to their original positions in requests
+    UnpinSongs { ids: Vec<String> },
+
     /// Advance to the next song faster
     Tempo { nick: String },
 
     /// Ready to sing upcoming song
     Bingo { nick: String },
 
+    /// Removes a nick that left the channel from the current mode's votes
+    /// without changing modes
+    RemoveNick { nick: String },
+
     /// Song is finished
     Skål,
 
-    /// Responds with list of song requests
-    ListSongs,
+    /// Responds with list of song requests, optionally only with a tag
+    ListSongs { tag: Option<String> },
+
+    /// Responds with the list of songs requested by users
+    ListRequests,
+
+    /// Responds with the list of backup songs
+    ListBackup,
+
+    /// Responds with the list of prioritized songs
+    ListFirst,
+
+    /// Overrides the timeout of a timed mode until the bot restarts
+    SetModeTimeout { mode: String, seconds: u64 },
+
+    /// Opens song requests if they are closed and vice versa, overriding
+    /// the configured request window until the bot restarts
+    ToggleRequestsOpen,
+
+    /// Adds a song from the songleader's queues to the music queue
+    PlaySongbookSong { song_id: String },
+
+    /// Sent by the songleader whenever it changes modes, for other
+    /// subsystems to react to. The modes are boxed since they carry song
+    /// data and would otherwise bloat every event on the bus.
+    ModeChanged {
+        old_mode: Box<Mode>,
+        new_mode: Box<Mode>,
+    },
+
+    /// Responds with the participants of the last `count` tempo rounds
+    TempoHistory { count: Option<usize> },
+
+    /// Checks all song links and reports broken ones
+    ValidateLinks,
+
+    /// Sends an announcement to the channel, optionally also with TTS,
+    /// without affecting the current mode
+    Announce { text: String, tts: bool },
+
+    /// Writes the serialized songleader state to a file in the dump
+    /// directory. A file name is generated if none is given.
+    ExportState { file_name: Option<String> },
+
+    /// Replaces the songleader state with one previously exported to the
+    /// dump directory
+    ImportState { file_name: String },
 
     /// Forces tempo
     ForceTempo,
@@ -86,7 +176,39 @@ pub enum SongleaderAction {
     Help,
 }
 
-#[derive(Default, Debug, Deserialize, Serialize, PartialEq)]
+/// Time when [Mode::Tempo] ends if not enough nicks have typed "!tempo",
+/// `deadline` after entering the mode minus `reduction` for each nick
+fn tempo_deadline(
+    init_t: Instant,
+    nicks: &HashSet<String>,
+    deadline: Duration,
+    reduction: Duration,
+) -> Instant {
+    let deadline = deadline.saturating_sub(reduction.saturating_mul(nicks.len() as u32));
+
+    // Fall back to the longest settable timeout rather than panicking if the
+    // deadline doesn't fit in an Instant
+    init_t
+        .checked_add(deadline)
+        .unwrap_or(init_t + Duration::from_secs(MAX_MODE_TIMEOUT_SECS))
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct TempoRound {
+    /// Running number of the round
+    pub round: u32,
+
+    /// Nicknames that typed "!tempo" during the round
+    pub nicks: Vec<String>,
+
+    /// Whether the round ended by reaching the tempo deadline
+    pub timeout: bool,
+
+    /// Unix timestamp in milliseconds of when the round started
+    pub entered_at: u64,
+}
+
+#[derive(Clone, Default, Debug, Deserialize, Serialize, PartialEq)]
 pub enum Mode {
     /// Songleader is inactive. Effectively pauses the songleader.
     #[default]
@@ -95,9 +217,9 @@ pub enum Mode {
     /// Songleader is playing its starting routine
     Starting,
 
-    /// Songleader is waiting to sing next song.  Waits until [NUM_TEMPO_NICKS]
-    /// have typed "!tempo" or until [TEMPO_DEADLINE] has passed. Each "!tempo"
-    /// reduces the deadline by [TEMPO_DEADLINE_REDUCTION].
+    /// Songleader is waiting to sing next song.  Waits until `num_tempo_nicks`
+    /// have typed "!tempo" or until `tempo_deadline_secs` has passed. Each
+    /// "!tempo" reduces the deadline by `tempo_deadline_reduction_secs`.
     Tempo {
         /// Set of nicknames that have typed "!tempo"
         nicks: HashSet<String>,
@@ -108,7 +230,7 @@ pub enum Mode {
     },
 
     /// Songleader is waiting for everyone to be ready to sing next song.  Waits
-    /// until [NUM_BINGO_NICKS] have types "!bingo".
+    /// until `num_bingo_nicks` have types "!bingo".
     Bingo {
         /// Set of nicknames that have typed "!bingo"
         nicks: HashSet<String>,
@@ -121,6 +243,34 @@ pub enum Mode {
     Singing,
 }
 
+impl Mode {
+    /// Human readable description of the mode and its progress
+    pub fn describe(&self, config: &SongleaderConfig) -> String {
+        match self {
+            Mode::Inactive => "Inactive".to_string(),
+            Mode::Starting => "Starting".to_string(),
+            Mode::Tempo { nicks, .. } => {
+                format!("Tempo ({}/{})", nicks.len(), config.num_tempo_nicks)
+            }
+            Mode::Bingo { nicks, .. } => {
+                format!("Bingo ({}/{})", nicks.len(), config.num_bingo_nicks)
+            }
+            Mode::Singing => "Singing".to_string(),
+        }
+    }
+
+    /// Name of the mode as used in config
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mode::Inactive => "inactive",
+            Mode::Starting => "starting",
+            Mode::Tempo { .. } => "tempo",
+            Mode::Bingo { .. } => "bingo",
+            Mode::Singing => "singing",
+        }
+    }
+}
+
 #[derive(Default, Debug, Deserialize, Serialize)]
 pub struct SongleaderState {
     /// List of songs that the songleader will sing first
@@ -134,28 +284,99 @@ pub struct SongleaderState {
 
     /// Current mode of the songleader
     mode: Mode,
+
+    /// Positions in requests that pinned songs were moved from, used to
+    /// restore the request order when unpinning
+    #[serde(default)]
+    pinned_positions: HashMap<String, usize>,
+
+    /// The last [TEMPO_HISTORY_LEN] tempo rounds
+    #[serde(default)]
+    tempo_history: Vec<TempoRound>,
+
+    /// Path that the state is persisted to
+    #[serde(skip)]
+    state_file: PathBuf,
 }
 
 impl SongleaderState {
-    async fn read_or_default() -> Self {
-        let res = tokio::fs::read(SONGLEADER_STATE_FILE).await;
+    async fn read_or_default(state_file: PathBuf) -> Self {
+        let res = tokio::fs::read(&state_file).await;
 
-        match res {
+        let state = match res {
             Ok(res) => serde_json::from_slice(&res).unwrap_or_default(),
             Err(e) => {
                 info!("Error while reading songleader state: {:?}", e);
                 info!("Falling back to default state.");
                 SongleaderState::default()
             }
+        };
+
+        let mut state = SongleaderState {
+            state_file,
+            ..state
+        };
+
+        let removed = state.deduplicate_across_queues();
+        if !removed.is_empty() {
+            warn!("Removed duplicate songs from songleader state: {removed:?}");
+        }
+
+        state
+    }
+
+    /// Serializes the state for inspection by admins
+    pub fn export(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// Deserializes a state previously serialized with [Self::export]
+    pub fn import(json: serde_json::Value) -> Result<SongleaderState> {
+        let mut state: SongleaderState = serde_json::from_value(json)?;
+
+        let removed = state.deduplicate_across_queues();
+        if !removed.is_empty() {
+            warn!("Removed duplicate songs from imported songleader state: {removed:?}");
         }
+
+        Ok(state)
+    }
+
+    /// Removes songs whose id already appeared earlier in the first songs,
+    /// requests or backup songs, checked in that order of priority. Also
+    /// forgets pinned positions of songs that are no longer prioritized.
+    /// Returns the ids of the removed songs.
+    pub fn deduplicate_across_queues(&mut self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut removed = vec![];
+
+        let mut keep_first = |song: &SongbookSong| {
+            let is_first = seen.insert(song.id.clone());
+            if !is_first {
+                removed.push(song.id.clone());
+            }
+            is_first
+        };
+
+        self.first_songs.retain(&mut keep_first);
+        self.requests.retain(&mut keep_first);
+        self.backup.retain(&mut keep_first);
+
+        let first_songs = &self.first_songs;
+        self.pinned_positions
+            .retain(|id, _| first_songs.iter().any(|song| &song.id == id));
+
+        removed
     }
 
     fn persist(&self) {
         let json = serde_json::to_string_pretty(self);
         match json {
             Ok(json) => {
+                let state_file = self.state_file.clone();
+
                 tokio::spawn(async move {
-                    let res = tokio::fs::write(SONGLEADER_STATE_FILE, json).await;
+                    let res = persist::write_atomically(&state_file, json).await;
 
                     if let Err(e) = res {
                         error!("Error while writing songleader state: {:?}", e);
@@ -178,25 +399,129 @@ impl SongleaderState {
         songs
     }
 
-    fn add_request(&mut self, song: SongbookSong) -> Result<SongbookSong> {
-        let songs = self.get_songs();
+    /// Returns up to `count` songs in the order [Self::pop_next_song] would
+    /// consider them, without removing anything. Only the first songs are
+    /// popped in this exact order, requests and backup songs are picked at
+    /// random.
+    pub fn preview_next_songs(&self, count: usize) -> Vec<&SongbookSong> {
+        self.first_songs
+            .iter()
+            .chain(self.requests.iter())
+            .chain(self.backup.iter())
+            .take(count)
+            .collect()
+    }
+
+    fn start_tempo_round(&mut self) {
+        let round = self
+            .tempo_history
+            .last()
+            .map(|round| round.round + 1)
+            .unwrap_or(1);
+
+        self.tempo_history.push(TempoRound {
+            round,
+            nicks: vec![],
+            timeout: false,
+            entered_at: playback::now_millis(),
+        });
+
+        if self.tempo_history.len() > TEMPO_HISTORY_LEN {
+            self.tempo_history.remove(0);
+        }
+    }
+
+    fn finish_tempo_round(&mut self, nicks: Vec<String>, timeout: bool) {
+        if let Some(round) = self.tempo_history.last_mut() {
+            round.nicks = nicks;
+            round.timeout = timeout;
+        }
+    }
+
+    /// Finds a song by id, searching the first songs, requests and backup
+    /// songs in that order
+    pub fn get_song_by_id(&self, id: &str) -> Option<&SongbookSong> {
+        self.first_songs
+            .iter()
+            .chain(self.requests.iter())
+            .chain(self.backup.iter())
+            .find(|song| song.id == id)
+    }
 
-        if songs.contains(&song) {
+    /// Case insensitively checks whether any song has the given title. This
+    /// is a best-effort check since not all songs have a title set.
+    pub fn contains_song_by_title(&self, title: &str) -> bool {
+        let title = title.to_lowercase();
+
+        self.first_songs
+            .iter()
+            .chain(self.requests.iter())
+            .chain(self.backup.iter())
+            .filter_map(|song| song.title.as_ref())
+            .any(|other_title| other_title.to_lowercase() == title)
+    }
+
+    /// Adds a song request, rejecting duplicates and requests from nicks
+    /// that already have `max_requests_per_user` pending requests
+    fn add_request(
+        &mut self,
+        song: SongbookSong,
+        fuzzy_dedup_ratio: f64,
+        max_requests_per_user: Option<usize>,
+    ) -> Result<SongbookSong> {
+        if self.get_song_by_id(&song.id).is_some() {
             return Err(anyhow!("Song already requested"));
         }
 
+        let songs = self.get_songs();
+
+        if let Some(title) = &song.title {
+            let title = songbook::normalize_title_for_dedup(title);
+
+            let similar_song = songs.iter().find(|other| {
+                other.title.as_ref().is_some_and(|other_title| {
+                    let other_title = songbook::normalize_title_for_dedup(other_title);
+                    songbook::is_similar_title(&title, &other_title, fuzzy_dedup_ratio)
+                })
+            });
+
+            if let Some(similar_song) = similar_song {
+                return Err(anyhow!("Similar song already requested: '{similar_song}'"));
+            }
+        }
+
+        if let (Some(nick), Some(max)) = (&song.queued_by, max_requests_per_user) {
+            if self.request_count_by_user(nick) >= max {
+                return Err(anyhow!(
+                    "{nick} already has {max} pending requests, wait until one of them has been sung"
+                ));
+            }
+        }
+
         self.requests.push(song.clone());
+
+        let removed = self.deduplicate_across_queues();
+        if !removed.is_empty() {
+            warn!("Removed duplicate songs after adding request: {removed:?}");
+        }
+
         self.persist();
 
         Ok(song)
     }
 
     fn rm_song_by_id(&mut self, id: String) -> Result<SongbookSong> {
+        if self.get_song_by_id(&id).is_none() {
+            return Err(anyhow!("Song not found by id {id}"));
+        }
+
         let index = self
             .requests
             .iter()
             .position(|song| song.id == id)
-            .ok_or_else(|| anyhow!("Song not found by id {id}"))?;
+            .ok_or_else(|| {
+                anyhow!("Song {id} is not a request, it may be prioritized or a backup song")
+            })?;
 
         let song = self.requests.remove(index);
         self.persist();
@@ -204,6 +529,14 @@ impl SongleaderState {
         Ok(song)
     }
 
+    /// Counts the song requests queued by `nick`
+    pub fn request_count_by_user(&self, nick: &str) -> usize {
+        self.requests
+            .iter()
+            .filter(|song| song.queued_by.as_deref() == Some(nick))
+            .count()
+    }
+
     fn rm_song_by_nick(&mut self, nick: String) -> Result<SongbookSong> {
         let index = self
             .requests
@@ -217,8 +550,204 @@ impl SongleaderState {
         Ok(song)
     }
 
+    fn move_request_to_first(&mut self, id: String) -> Result<SongbookSong> {
+        let index = self
+            .requests
+            .iter()
+            .position(|song| song.id == id)
+            .ok_or_else(|| anyhow!("Song not found in requests by id {id}"))?;
+
+        let song = self.requests.remove(index);
+        self.first_songs.push_front(song.clone());
+        self.persist();
+
+        Ok(song)
+    }
+
+    fn move_first_to_requests(&mut self, id: String) -> Result<SongbookSong> {
+        let song = self
+            .first_songs
+            .iter()
+            .position(|song| song.id == id)
+            .and_then(|index| self.first_songs.remove(index))
+            .ok_or_else(|| anyhow!("Song not found in prioritized songs by id {id}"))?;
+
+        self.pinned_positions.remove(&id);
+        self.requests.push(song.clone());
+        self.persist();
+
+        Ok(song)
+    }
+
+    fn promote_next_request(&mut self) -> Result<SongbookSong> {
+        if self.requests.is_empty() {
+            return Err(anyhow!("No requests to promote"));
+        }
+
+        let song = self.requests.remove(0);
+        self.first_songs.push_front(song.clone());
+        self.persist();
+
+        Ok(song)
+    }
+
+    fn demote_first_song(&mut self) -> Result<SongbookSong> {
+        let song = self
+            .first_songs
+            .pop_front()
+            .ok_or_else(|| anyhow!("No prioritized songs to demote"))?;
+
+        self.pinned_positions.remove(&song.id);
+        self.requests.insert(0, song.clone());
+        self.persist();
+
+        Ok(song)
+    }
+
+    /// Swaps the positions of two songs in the prioritized songs or requests.
+    /// Swapping a request with a prioritized song prioritizes the request in
+    /// its place. Two requests only swap places in the list, as requests are
+    /// picked at random.
+    fn swap_requests(&mut self, id_a: String, id_b: String) -> Result<()> {
+        let position = |id: &str| {
+            let in_first_songs = self.first_songs.iter().position(|song| song.id == id);
+            let in_requests = self.requests.iter().position(|song| song.id == id);
+
+            match (in_first_songs, in_requests) {
+                (Some(index), _) => Ok((true, index)),
+                (None, Some(index)) => Ok((false, index)),
+                (None, None) => Err(anyhow!("Song not found in requests by id {id}")),
+            }
+        };
+
+        let (a_is_first, index_a) = position(&id_a)?;
+        let (b_is_first, index_b) = position(&id_b)?;
+
+        match (a_is_first, b_is_first) {
+            (true, true) => self.first_songs.swap(index_a, index_b),
+            (false, false) => self.requests.swap(index_a, index_b),
+            (true, false) => {
+                std::mem::swap(&mut self.first_songs[index_a], &mut self.requests[index_b])
+            }
+            (false, true) => {
+                std::mem::swap(&mut self.requests[index_a], &mut self.first_songs[index_b])
+            }
+        }
+
+        // A song that left the prioritized songs has no pinned position to
+        // return to anymore
+        for id in [&id_a, &id_b] {
+            if !self.first_songs.iter().any(|song| &song.id == id) {
+                self.pinned_positions.remove(id);
+            }
+        }
+
+        self.persist();
+
+        Ok(())
+    }
+
+    /// Prioritizes all requests in the order given by `new_order`, which must
+    /// contain exactly the ids of the current requests. They are sung in that
+    /// order after the songs that were already prioritized.
+    fn reorder_requests(&mut self, new_order: Vec<String>) -> Result<()> {
+        let mut songs: HashMap<String, SongbookSong> = self
+            .requests
+            .iter()
+            .map(|song| (song.id.clone(), song.clone()))
+            .collect();
+
+        let reordered = new_order
+            .iter()
+            .map(|id| {
+                songs
+                    .remove(id)
+                    .ok_or_else(|| anyhow!("Song not found in requests by id {id}"))
+            })
+            .collect::<Result<Vec<SongbookSong>>>()?;
+
+        if !songs.is_empty() {
+            let missing: Vec<&str> = songs.keys().map(|id| id.as_str()).collect();
+            return Err(anyhow!("Missing song ids: {}", missing.join(", ")));
+        }
+
+        self.requests.clear();
+        self.first_songs.extend(reordered);
+        self.persist();
+
+        Ok(())
+    }
+
+    fn pin_songs(&mut self, ids: Vec<String>) -> Vec<SongbookSong> {
+        let original_positions: HashMap<String, usize> = self
+            .requests
+            .iter()
+            .enumerate()
+            .map(|(index, song)| (song.id.clone(), index))
+            .collect();
+
+        let mut pinned = vec![];
+
+        for id in ids {
+            let index = self.requests.iter().position(|song| song.id == id);
+
+            match index {
+                Some(index) => {
+                    let song = self.requests.remove(index);
+
+                    if let Some(original_position) = original_positions.get(&id) {
+                        self.pinned_positions.insert(id, *original_position);
+                    }
+
+                    self.first_songs.push_back(song.clone());
+                    pinned.push(song);
+                }
+                None => warn!("Cannot pin song {id}, not found in requests"),
+            }
+        }
+
+        self.persist();
+
+        pinned
+    }
+
+    fn unpin_songs(&mut self, ids: Vec<String>) -> Vec<SongbookSong> {
+        let mut unpinned = vec![];
+
+        for id in ids {
+            let index = self.first_songs.iter().position(|song| song.id == id);
+
+            match index.and_then(|index| self.first_songs.remove(index)) {
+                Some(song) => {
+                    // Songs that were never pinned go to the end of requests
+                    let position = self
+                        .pinned_positions
+                        .remove(&id)
+                        .unwrap_or(self.requests.len());
+
+                    unpinned.push((position, song));
+                }
+                None => warn!("Cannot unpin song {id}, not found in first songs"),
+            }
+        }
+
+        // Inserting in ascending order of original position restores the
+        // original request order
+        unpinned.sort_by_key(|(position, _)| *position);
+
+        for (position, song) in &unpinned {
+            let position = (*position).min(self.requests.len());
+            self.requests.insert(position, song.clone());
+        }
+
+        self.persist();
+
+        unpinned.into_iter().map(|(_, song)| song).collect()
+    }
+
     pub fn pop_next_song(&mut self) -> Option<SongbookSong> {
         if let Some(song) = self.first_songs.pop_front() {
+            self.pinned_positions.remove(&song.id);
             return Some(song);
         }
 
@@ -244,12 +773,23 @@ pub struct Songleader {
     bus: EventBus,
 
     config: Config,
+
+    /// Cancels a delayed music pause that hasn't happened yet
+    pending_music_pause: Option<CancellationToken>,
+
+    /// Time until [Mode::Tempo] ends, `tempo_deadline_secs` unless
+    /// overridden at runtime. Intentionally not persisted.
+    tempo_deadline: Duration,
+
+    /// Admin override of whether song requests are open, ignoring the
+    /// configured request window. Intentionally not persisted.
+    requests_open_override: Option<bool>,
 }
 
 impl Songleader {
     /// Creates a new [Songleader] struct
     pub async fn create(bus: &EventBus, config: &Config) -> Self {
-        let state = SongleaderState::read_or_default().await;
+        let state = SongleaderState::read_or_default(config.state.songleader_state_path()).await;
 
         debug!("Initial songleader state:\n{:#?}", state);
 
@@ -257,6 +797,14 @@ impl Songleader {
             state,
             bus: bus.clone(),
             config: config.clone(),
+            pending_music_pause: None,
+            tempo_deadline: Duration::from_secs(
+                config
+                    .songleader
+                    .tempo_deadline_secs
+                    .min(MAX_MODE_TIMEOUT_SECS),
+            ),
+            requests_open_override: None,
         }
     }
 
@@ -265,16 +813,78 @@ impl Songleader {
     fn set_mode(&mut self, mode: Mode) {
         debug!("Transitioning to mode: {:?}", mode);
 
-        self.state.mode = mode;
+        if let Some(pending_music_pause) = self.pending_music_pause.take() {
+            pending_music_pause.cancel();
+        }
+
+        let finished_round = match &self.state.mode {
+            Mode::Tempo { init_t, nicks } => {
+                let timeout = Instant::now() > self.tempo_deadline_for(*init_t, nicks);
+                let mut nicks: Vec<String> = nicks.iter().cloned().collect();
+                nicks.sort();
+
+                Some((nicks, timeout))
+            }
+            _ => None,
+        };
+
+        if let Some((nicks, timeout)) = finished_round {
+            self.state.finish_tempo_round(nicks, timeout);
+        }
+
+        if let Mode::Tempo { .. } = mode {
+            self.state.start_tempo_round();
+        }
+
+        // Keep the channel quiet while singing
+        if self.config.irc.irc_set_moderated_on_singing {
+            let was_singing = self.state.mode == Mode::Singing;
+            let is_singing = mode == Mode::Singing;
+
+            if is_singing && !was_singing {
+                self.bus.send(Event::Irc(IrcAction::SetChannelMode {
+                    mode: "+m".to_string(),
+                }));
+            } else if was_singing && !is_singing {
+                self.bus.send(Event::Irc(IrcAction::SetChannelMode {
+                    mode: "-m".to_string(),
+                }));
+            }
+        }
+
+        let old_mode = std::mem::replace(&mut self.state.mode, mode);
         self.state.persist();
+        self.update_topic();
+
+        self.bus
+            .send(Event::Songleader(SongleaderAction::ModeChanged {
+                old_mode: Box::new(old_mode),
+                new_mode: Box::new(self.state.mode.clone()),
+            }));
+    }
+
+    /// Sends the songleader status shown in the channel topic
+    fn update_topic(&self) {
+        self.bus.send(Event::Irc(IrcAction::SongleaderTopicInfo {
+            mode: self.state.mode.describe(&self.config.songleader),
+        }));
     }
 
-    /// Convenience method for sending text to speech messages
+    /// Convenience method for sending text to speech messages, using the
+    /// voice configured for the current mode if any
     fn tts_say(&self, text: &str) {
+        let voice = self
+            .config
+            .songleader
+            .mode_voices
+            .get(self.state.mode.name())
+            .cloned();
+
         self.bus
             .send(Event::TextToSpeech(TextToSpeechAction::Speak {
                 text: text.to_string(),
                 prio: Priority::High,
+                voice,
             }));
     }
 
@@ -284,6 +894,52 @@ impl Songleader {
             .send(Event::Irc(IrcAction::SendMsg(msg.to_string())));
     }
 
+    /// Time when the tempo round entered at `init_t` times out with the
+    /// current deadline settings
+    fn tempo_deadline_for(&self, init_t: Instant, nicks: &HashSet<String>) -> Instant {
+        tempo_deadline(
+            init_t,
+            nicks,
+            self.tempo_deadline,
+            Duration::from_secs(self.config.songleader.tempo_deadline_reduction_secs),
+        )
+    }
+
+    /// Whether song requests are accepted right now, either because of the
+    /// admin override or the configured request window
+    fn requests_open(&self) -> bool {
+        self.requests_open_override.unwrap_or_else(|| {
+            is_within_window(
+                Local::now().time(),
+                self.config.songleader.requests_open_at,
+                self.config.songleader.requests_close_at,
+            )
+        })
+    }
+
+    /// Convenience method for responding to a single user
+    fn irc_reply(&self, nick: &str, msg: &str) {
+        self.bus.send(Event::Irc(IrcAction::Reply {
+            nick: nick.to_string(),
+            msg: msg.to_string(),
+        }));
+    }
+
+    /// Sends a list of song titles, e.g. "Backup songs: a, b, c"
+    fn say_song_list<'a>(&self, name: &str, songs: impl Iterator<Item = &'a SongbookSong>) {
+        let titles: Vec<String> = songs
+            .map(|song| song.title.clone().unwrap_or_else(|| song.id.clone()))
+            .collect();
+
+        let msg = if titles.is_empty() {
+            format!("No {} found :(", name.to_lowercase())
+        } else {
+            format!("{name}: {}", titles.join(", "))
+        };
+
+        self.irc_say(&msg);
+    }
+
     /// Convenience method for (dis)allowing music playback
     fn allow_music_playback(&self, allow: bool) {
         if allow {
@@ -293,6 +949,21 @@ impl Songleader {
         }
     }
 
+    /// Pauses music after `delay`, unless the mode changes before that
+    fn pause_music_after(&mut self, delay: Duration) {
+        let token = CancellationToken::new();
+        self.pending_music_pause = Some(token.clone());
+
+        let bus = self.bus.clone();
+
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = token.cancelled() => {}
+                _ = sleep(delay) => bus.send(Event::Playback(PlaybackAction::Pause)),
+            }
+        });
+    }
+
     /// Convenience method for (dis)allowing low priority speech messages
     fn allow_low_prio_speech(&self, allow: bool) {
         if allow {
@@ -330,13 +1001,16 @@ impl Songleader {
         let mk_songbook_song = |title: &str, id: &str, page: usize| {
             let id = format!("tf-sangbok-150-{}", id);
             let songbook_url = &self.config.songbook.songbook_url;
+            let url = format!("{songbook_url}/{id}");
 
             SongbookSong {
-                url: Some(format!("{songbook_url}/{id}")),
+                url: Some(url.clone()),
                 id,
                 title: Some(title.to_string()),
                 book: Some(format!("TF:s Sångbok 150 – s. {page}")),
                 queued_by: None,
+                page_url: Some(url),
+                tags: vec![],
             }
         };
 
@@ -345,6 +1019,7 @@ impl Songleader {
             mk_songbook_song("Fjärran han dröjer", "fjarran-han-drojer", 45),
         ]
         .into();
+        self.state.pinned_positions.clear();
 
         self.state.requests = vec![];
 
@@ -379,12 +1054,14 @@ Have fun, and don't drown in the shower!
             )
         );
 
-        for line in welcome_text.split('\n') {
+        let lines: Vec<&str> = welcome_text.split('\n').collect();
+
+        for line in &lines {
             self.irc_say(line);
-            sleep(ANTI_FLOOD_DELAY).await;
         }
 
-        sleep(3 * SECOND).await;
+        // Wait for the rate limited welcome text to be sent before singing
+        sleep(irc::send_duration(lines.len()) + 3 * SECOND).await;
 
         self.irc_say("*sjunger:*");
 
@@ -433,11 +1110,23 @@ Have fun, and don't drown in the shower!
                     song: song.clone(),
                 });
 
-                self.allow_music_playback(false);
+                let title = song.title.as_ref().unwrap_or(&song.id);
+                let title = localization::tts::normalize_title(
+                    title,
+                    &self.config.tts.tts_language,
+                    &self.config.tts.tts_abbreviations,
+                );
+                let tts_song = match &song.book {
+                    Some(book) => format!("{title}, {book}"),
+                    None => title,
+                };
+                self.tts_say(&format!("Nästa sång kommer nu... {tts_song}"));
 
-                self.tts_say(&format!("Nästa sång kommer nu... {song}"));
+                // Let the music fade out under the announcement
+                let delay = Duration::from_secs(self.config.songleader.bingo_music_fade_delay_secs);
+                self.pause_music_after(delay);
 
-                if let Some(url) = &song.url {
+                if let Some(url) = song.link() {
                     self.irc_say(&format!("Next song coming up: {song}. {}", url));
                 } else {
                     self.irc_say(&format!("Next song coming up: {song}"));
@@ -456,6 +1145,8 @@ Have fun, and don't drown in the shower!
     pub async fn enter_singing_mode(&mut self) {
         self.set_mode(Mode::Singing);
 
+        // Entering singing mode cancels a delayed pause from bingo mode
+        self.allow_music_playback(false);
         self.allow_low_prio_speech(false);
 
         self.tts_say("PLING PLONG");
@@ -485,6 +1176,29 @@ pub async fn init(bus: &EventBus, config: &Config) {
 
     handle_incoming_event_loop(bus.clone(), config.clone(), songleader.clone());
     check_tempo_timeout_loop(songleader.clone());
+
+    if let Some(interval) = config.state.heartbeat_interval() {
+        persist::start_heartbeat(songleader, interval, |songleader| {
+            songleader.state.persist()
+        });
+    }
+}
+
+/// Checks whether `now` is within the window from `open_at` to `close_at`.
+/// A missing bound leaves that side of the window open, and a window that
+/// closes before it opens spans midnight.
+fn is_within_window(
+    now: NaiveTime,
+    open_at: Option<NaiveTime>,
+    close_at: Option<NaiveTime>,
+) -> bool {
+    match (open_at, close_at) {
+        (None, None) => true,
+        (Some(open_at), None) => now >= open_at,
+        (None, Some(close_at)) => now < close_at,
+        (Some(open_at), Some(close_at)) if open_at <= close_at => now >= open_at && now < close_at,
+        (Some(open_at), Some(close_at)) => now >= open_at || now < close_at,
+    }
 }
 
 /// Polls for tempo timeouts every second
@@ -494,18 +1208,35 @@ fn check_tempo_timeout_loop(songleader: Arc<RwLock<Songleader>>) {
             sleep(SECOND).await;
             let mut songleader = songleader.write().await;
 
-            if let Mode::Tempo { init_t, nicks } = &mut songleader.state.mode {
-                let timeout =
-                    *init_t + TEMPO_DEADLINE - TEMPO_DEADLINE_REDUCTION * nicks.len() as u32;
-
-                if Instant::now() > timeout {
-                    songleader.enter_bingo_mode();
+            if let Mode::Tempo { init_t, nicks } = &songleader.state.mode {
+                if Instant::now() > songleader.tempo_deadline_for(*init_t, nicks) {
+                    enter_bingo_mode_or_restart_tempo(
+                        &mut songleader,
+                        Songleader::enter_bingo_mode,
+                    );
                 }
             }
         }
     });
 }
 
+/// Runs `enter_bingo_mode` after a tempo timeout. A panic would otherwise end
+/// the timeout loop and with it all tempo timeouts for the rest of the party,
+/// so tempo is restarted instead.
+fn enter_bingo_mode_or_restart_tempo(
+    songleader: &mut Songleader,
+    enter_bingo_mode: impl FnOnce(&mut Songleader),
+) {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| enter_bingo_mode(songleader)));
+
+    if result.is_err() {
+        error!("Panic while entering bingo mode after tempo timeout");
+        songleader.enter_tempo_mode();
+        songleader
+            .irc_say("Error: Something went wrong while picking the next song, restarting tempo");
+    }
+}
+
 /// Loop over incoming events on the bus
 fn handle_incoming_event_loop(bus: EventBus, config: Config, songleader: Arc<RwLock<Songleader>>) {
     tokio::spawn(async move {
@@ -538,22 +1269,102 @@ async fn handle_incoming_event(
 
     match action {
         SongleaderAction::RequestSongUrl { url, queued_by } => {
+            if !songleader.requests_open() {
+                songleader.irc_reply(&queued_by, REQUESTS_CLOSED_MSG);
+                return;
+            }
+
             // Don't hold onto the lock while fetching song info
             drop(songleader);
 
             let song = songbook::get_song_info(&url, &config, &queued_by).await;
 
             let mut songleader = songleader_rwlock.write().await;
-            let result = song.and_then(|song| songleader.state.add_request(song));
 
-            match result {
-                Ok(song) => songleader.irc_say(&format!("Added {song} to requests")),
-                Err(e) => songleader.irc_say(&format!("Error while requesting song: {:?}", e)),
+            let song = match song {
+                Ok(song) => song,
+                Err(e) => {
+                    warn!("Error while getting songbook song info: {:?}", e);
+                    songleader.irc_reply(&queued_by, &format!("Error while requesting song: {e}"));
+                    return;
+                }
+            };
+
+            let result = songleader.state.add_request(
+                song,
+                config.songbook.fuzzy_dedup_ratio(),
+                config.songbook.max_requests_per_user(),
+            );
+
+            match result {
+                Ok(song) => {
+                    let request_count = songleader.state.request_count_by_user(&queued_by);
+                    songleader.irc_reply(
+                        &queued_by,
+                        &format!("Added {song} to requests ({request_count} pending)"),
+                    )
+                }
+                Err(e) => songleader
+                    .irc_reply(&queued_by, &format!("Error while requesting song: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::RequestSongByTag { tag, queued_by } => {
+            let songs = songleader.state.get_songs();
+
+            // Don't hold onto the lock while searching the songbook
+            drop(songleader);
+
+            let urls = songbook::search_by_tag(&tag, &config).await;
+
+            let url = urls.map(|urls| {
+                let urls: Vec<String> = urls
+                    .into_iter()
+                    .filter(|url| !songs.iter().any(|song| song.url.as_ref() == Some(url)))
+                    .collect();
+
+                urls.choose(&mut rand::thread_rng()).cloned()
+            });
+
+            match url {
+                Ok(Some(url)) => bus.send(Event::Songleader(SongleaderAction::RequestSongUrl {
+                    url,
+                    queued_by,
+                })),
+                Ok(None) => songleader_rwlock
+                    .read()
+                    .await
+                    .irc_say(&format!("No unrequested songs found with tag {tag}")),
+                Err(e) => songleader_rwlock
+                    .read()
+                    .await
+                    .irc_say(&format!("Error while requesting song: {:?}", e)),
             }
         }
 
         SongleaderAction::RequestSong { song } => {
-            let result = songleader.state.add_request(song);
+            if !songleader.requests_open() {
+                songleader.irc_say(REQUESTS_CLOSED_MSG);
+                return;
+            }
+
+            // Songs requested by title alone have the title as their id, so
+            // the id based duplicate check won't catch them
+            let duplicate_title = song.url.is_none()
+                && song
+                    .title
+                    .as_ref()
+                    .is_some_and(|title| songleader.state.contains_song_by_title(title));
+
+            let result = if duplicate_title {
+                Err(anyhow!("Song already requested"))
+            } else {
+                songleader.state.add_request(
+                    song,
+                    config.songbook.fuzzy_dedup_ratio(),
+                    config.songbook.max_requests_per_user(),
+                )
+            };
 
             match result {
                 Ok(song) => songleader.irc_say(&format!("Added {song} to requests")),
@@ -561,6 +1372,28 @@ async fn handle_incoming_event(
             }
         }
 
+        SongleaderAction::BulkRequestSongs { songs } => {
+            let total = songs.len();
+            let added = songs
+                .into_iter()
+                .filter_map(|song| {
+                    let id = song.id.clone();
+
+                    // Bulk imports are prepared by admins, so don't limit them
+                    songleader
+                        .state
+                        .add_request(song, config.songbook.fuzzy_dedup_ratio(), None)
+                        .map_err(|e| info!("Skipped bulk requested song {id}: {e}"))
+                        .ok()
+                })
+                .count();
+
+            songleader.irc_say(&format!(
+                "Bulk request: {added} added, {} skipped",
+                total - added
+            ));
+        }
+
         SongleaderAction::RmSongById { id } => {
             let result = songleader.state.rm_song_by_id(id);
 
@@ -579,14 +1412,98 @@ async fn handle_incoming_event(
             }
         }
 
+        SongleaderAction::PrioritizeSong { id } => {
+            let result = songleader.state.move_request_to_first(id);
+
+            match result {
+                Ok(song) => {
+                    songleader.irc_say(&format!("Prioritized {song}, it will be sung next"))
+                }
+                Err(e) => songleader.irc_say(&format!("Error while prioritizing song: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::DeprioritizeSong { id } => {
+            let result = songleader.state.move_first_to_requests(id);
+
+            match result {
+                Ok(song) => songleader.irc_say(&format!("Moved {song} back to requests")),
+                Err(e) => songleader.irc_say(&format!("Error while deprioritizing song: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::PromoteNextRequest => {
+            let result = songleader.state.promote_next_request();
+
+            match result {
+                Ok(song) => {
+                    songleader.irc_say(&format!("Prioritized {song}, it will be sung next"))
+                }
+                Err(e) => songleader.irc_say(&format!("Error while promoting song: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::DemoteFirstSong => {
+            let result = songleader.state.demote_first_song();
+
+            match result {
+                Ok(song) => songleader.irc_say(&format!("Moved {song} back to requests")),
+                Err(e) => songleader.irc_say(&format!("Error while demoting song: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::SwapRequests { id_a, id_b } => {
+            let msg = format!("Swapped {id_a} and {id_b}");
+            let result = songleader.state.swap_requests(id_a, id_b);
+
+            match result {
+                Ok(()) => songleader.irc_say(&msg),
+                Err(e) => songleader.irc_say(&format!("Error while swapping songs: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::ReorderRequests { new_order } => {
+            let result = songleader.state.reorder_requests(new_order);
+
+            match result {
+                Ok(()) => {
+                    songleader.irc_say("Reordered requests, they will be sung in the new order")
+                }
+                Err(e) => songleader.irc_say(&format!("Error while reordering songs: {:?}", e)),
+            }
+        }
+
+        SongleaderAction::PinSongs { ids } => {
+            let songs = songleader.state.pin_songs(ids);
+            let titles: Vec<String> = songs.iter().map(|song| song.to_string()).collect();
+
+            if titles.is_empty() {
+                songleader.irc_say("No matching requests found to pin");
+            } else {
+                songleader.irc_say(&format!("Pinned songs: {}", titles.join(", ")));
+            }
+        }
+
+        SongleaderAction::UnpinSongs { ids } => {
+            let songs = songleader.state.unpin_songs(ids);
+            let titles: Vec<String> = songs.iter().map(|song| song.to_string()).collect();
+
+            if titles.is_empty() {
+                songleader.irc_say("No matching pinned songs found");
+            } else {
+                songleader.irc_say(&format!("Unpinned songs: {}", titles.join(", ")));
+            }
+        }
+
         SongleaderAction::Tempo { nick } => {
             if let Mode::Tempo { nicks, .. } = &mut songleader.state.mode {
                 nicks.insert(nick);
 
-                if nicks.len() >= NUM_TEMPO_NICKS {
+                if nicks.len() >= config.songleader.num_tempo_nicks {
                     songleader.enter_bingo_mode();
                 } else {
                     songleader.state.persist();
+                    songleader.update_topic();
                 }
             }
         }
@@ -595,32 +1512,247 @@ async fn handle_incoming_event(
             if let Mode::Bingo { nicks, .. } = &mut songleader.state.mode {
                 nicks.insert(nick);
 
-                if nicks.len() >= NUM_BINGO_NICKS {
+                if nicks.len() >= config.songleader.num_bingo_nicks {
                     songleader.enter_singing_mode().await;
                 } else {
                     songleader.state.persist();
+                    songleader.update_topic();
                 }
             }
         }
 
+        SongleaderAction::RemoveNick { nick } => {
+            let removed = match &mut songleader.state.mode {
+                Mode::Tempo { nicks, .. } | Mode::Bingo { nicks, .. } => nicks.remove(&nick),
+                _ => false,
+            };
+
+            if removed {
+                songleader.state.persist();
+                songleader.update_topic();
+            }
+
+            let request_count = songleader.state.request_count_by_user(&nick);
+
+            if request_count > 0 {
+                songleader.irc_say(&format!(
+                    "Goodbye {nick}! Your {request_count} requested songs stay in the queue."
+                ));
+            }
+        }
+
         SongleaderAction::Skål => {
             if let Mode::Singing = &mut songleader.state.mode {
-                songleader.enter_tempo_mode();
+                let all_sung = songleader.state.get_songs().is_empty();
+
+                if all_sung && songleader.config.songleader.auto_end_on_empty {
+                    songleader.irc_say("All requested songs have been sung! Party is over.");
+                    songleader.enter_inactive_mode();
+                } else {
+                    songleader.enter_tempo_mode();
+                }
             }
         }
-        SongleaderAction::ListSongs => {
-            let songs = songleader.state.get_songs();
+        SongleaderAction::ListSongs { tag } => {
+            let matches_tag = |song: &SongbookSong| match &tag {
+                Some(tag) => song.has_tag(tag),
+                None => true,
+            };
+
+            let songs: Vec<&SongbookSong> = songleader
+                .state
+                .preview_next_songs(usize::MAX)
+                .into_iter()
+                .filter(|song| matches_tag(song))
+                .collect();
+
             let msg = if songs.is_empty() {
                 "No requested songs found :(".to_string()
             } else {
-                let songs_str: Vec<String> = songs
+                let num_first_songs = songleader
+                    .state
+                    .first_songs
+                    .iter()
+                    .filter(|song| matches_tag(song))
+                    .count();
+                let mut songs_str: Vec<String> = songs
                     .iter()
                     .map(|song| song.title.clone().unwrap_or_else(|| song.id.clone()))
                     .collect();
+
+                // Everything after the first songs gets picked at random
+                if let Some(song) = songs_str.get_mut(num_first_songs) {
+                    *song = format!("~random: {song}");
+                }
+
                 format!("Song requests: {}", songs_str.join(", "))
             };
             songleader.irc_say(&msg);
         }
+        SongleaderAction::ToggleRequestsOpen => {
+            let open = !songleader.requests_open();
+            songleader.requests_open_override = Some(open);
+
+            if open {
+                songleader.irc_say("Song requests are now open");
+            } else {
+                songleader.irc_say("Song requests are now closed");
+            }
+        }
+        SongleaderAction::SetModeTimeout { mode, seconds } => match mode.as_str() {
+            "tempo" => {
+                let seconds = seconds.min(MAX_MODE_TIMEOUT_SECS);
+                songleader.tempo_deadline = Duration::from_secs(seconds);
+                songleader.irc_say(&format!("Tempo mode now times out after {seconds}s"));
+            }
+            _ => songleader.irc_say(&format!(
+                "Error: {mode} mode has no timeout, timed modes are: tempo"
+            )),
+        },
+        SongleaderAction::PlaySongbookSong { song_id } => {
+            let song = songleader.state.get_song_by_id(&song_id).cloned();
+
+            match song {
+                Some(song) => songleader
+                    .bus
+                    .send(Event::Playback(PlaybackAction::Enqueue {
+                        song: song.into(),
+                    })),
+                None => songleader.irc_say(&format!("No song found by id {song_id}")),
+            }
+        }
+        SongleaderAction::ModeChanged { old_mode, new_mode } => {
+            debug!(
+                "Mode changed from {} to {}",
+                old_mode.name(),
+                new_mode.name()
+            )
+        }
+        SongleaderAction::ListRequests => {
+            songleader.say_song_list("Song requests", songleader.state.requests.iter())
+        }
+        SongleaderAction::ListBackup => {
+            songleader.say_song_list("Backup songs", songleader.state.backup.iter())
+        }
+        SongleaderAction::ListFirst => {
+            songleader.say_song_list("Prioritized songs", songleader.state.first_songs.iter())
+        }
+        SongleaderAction::TempoHistory { count } => {
+            let count = count.unwrap_or(DEFAULT_TEMPO_HISTORY_COUNT);
+            let history = &songleader.state.tempo_history;
+
+            let msg = if history.is_empty() {
+                "No tempo rounds yet".to_string()
+            } else {
+                let rounds: Vec<String> = history
+                    .iter()
+                    .rev()
+                    .take(count)
+                    .map(|round| {
+                        let timeout = if round.timeout { " (timeout)" } else { "" };
+                        let nicks = if round.nicks.is_empty() {
+                            "-".to_string()
+                        } else {
+                            round.nicks.join(", ")
+                        };
+
+                        format!("#{}{timeout}: {nicks}", round.round)
+                    })
+                    .collect();
+
+                format!("Tempo history: {}", rounds.join(" | "))
+            };
+
+            songleader.irc_say(&msg);
+        }
+        SongleaderAction::ValidateLinks => {
+            let songs = songleader.state.get_songs();
+
+            // Don't hold onto the lock while validating links
+            drop(songleader);
+
+            let broken = songbook::validate_links(&songs, &config).await;
+
+            let msg = if broken.is_empty() {
+                "No broken links found".to_string()
+            } else {
+                let broken_str: Vec<String> =
+                    broken.iter().map(|(id, e)| format!("{id} ({e})")).collect();
+                format!(
+                    "{} broken links found: {}",
+                    broken.len(),
+                    broken_str.join(", ")
+                )
+            };
+
+            songleader_rwlock.read().await.irc_say(&msg);
+        }
+        SongleaderAction::Announce { text, tts } => {
+            if tts {
+                songleader.tts_and_irc_say(&text);
+            } else {
+                songleader.irc_say(&text);
+            }
+        }
+        SongleaderAction::ExportState { file_name } => {
+            let file_name = file_name.unwrap_or_else(|| {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                format!("songleader-state-{now}.json")
+            });
+
+            let dump_dir = songleader.config.state.dump_dir();
+            let Some(path) = persist::file_in_dir(&dump_dir, &file_name) else {
+                songleader.irc_say(&format!("Error: Invalid file name {file_name}"));
+                return;
+            };
+
+            let result = match serde_json::to_string_pretty(&songleader.state.export()) {
+                Ok(json) => match tokio::fs::create_dir_all(&dump_dir).await {
+                    Ok(()) => persist::write_atomically(&path, json)
+                        .await
+                        .map_err(anyhow::Error::from),
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e.into()),
+            };
+
+            match result {
+                Ok(()) => songleader.irc_say(&format!(
+                    "Exported songleader state to {file_name}, import it with !song import-state {file_name}"
+                )),
+                Err(e) => songleader.irc_say(&format!("Error while exporting state: {:?}", e)),
+            }
+        }
+        SongleaderAction::ImportState { file_name } => {
+            let dump_dir = songleader.config.state.dump_dir();
+            let Some(path) = persist::file_in_dir(&dump_dir, &file_name) else {
+                songleader.irc_say(&format!("Error: Invalid file name {file_name}"));
+                return;
+            };
+
+            let state = tokio::fs::read(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(serde_json::from_slice::<serde_json::Value>(&json)?))
+                .and_then(SongleaderState::import);
+
+            match state {
+                Ok(state) => {
+                    let state_file = songleader.state.state_file.clone();
+                    songleader.state = SongleaderState {
+                        state_file,
+                        ..state
+                    };
+                    songleader.state.persist();
+                    songleader.update_topic();
+                    songleader.irc_say("Imported songleader state");
+                }
+                Err(e) => songleader.irc_say(&format!("Error while importing state: {:?}", e)),
+            }
+        }
         SongleaderAction::ForceTempo => songleader.enter_tempo_mode(),
         SongleaderAction::ForceBingo => songleader.enter_bingo_mode(),
         SongleaderAction::ForceSinging => songleader.enter_singing_mode().await,
@@ -633,21 +1765,654 @@ async fn handle_incoming_event(
                 return;
             }
 
-            // Avoid blocking current task by spawning a new one to
-            // flood the help text
-            let bus = bus.clone();
+            // Flood protection is handled by the IRC rate limiter
             let songbook_url = config.songbook.songbook_url;
-            tokio::spawn(async move {
-                let help_text = HELP_TEXT.replace(
-                    "songbook-url",
-                    &format!("{songbook_url}/tf-sangbok-150-teknologvisan"),
-                );
+            let help_text = HELP_TEXT.replace(
+                "songbook-url",
+                &format!("{songbook_url}/tf-sangbok-150-teknologvisan"),
+            );
 
-                for line in help_text.split('\n') {
-                    bus.send(Event::Irc(IrcAction::SendMsg(line.to_string())));
-                    sleep(ANTI_FLOOD_DELAY).await;
-                }
-            });
+            for line in help_text.split('\n') {
+                bus.send(Event::Irc(IrcAction::SendNotice(line.to_string())));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str) -> SongbookSong {
+        SongbookSong {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn ids<'a>(songs: impl IntoIterator<Item = &'a SongbookSong>) -> Vec<&'a str> {
+        songs.into_iter().map(|song| song.id.as_str()).collect()
+    }
+
+    fn state_with_requests(request_ids: &[&str]) -> SongleaderState {
+        SongleaderState {
+            requests: request_ids.iter().map(|id| song(id)).collect(),
+            state_file: std::env::temp_dir().join("irc-sitz-test-songleader_state.json"),
+            ..Default::default()
         }
     }
+
+    fn titled_song(id: &str, title: &str) -> SongbookSong {
+        SongbookSong {
+            title: Some(title.to_string()),
+            ..song(id)
+        }
+    }
+
+    #[tokio::test]
+    async fn add_request_rejects_near_duplicate_titles() {
+        let mut state = state_with_requests(&[]);
+        state.requests.push(titled_song("a", "Helan går"));
+
+        assert!(state
+            .add_request(titled_song("b", "Helan gar!"), 0.15, None)
+            .is_err());
+        assert!(state
+            .add_request(titled_song("c", "Halvan går"), 0.15, None)
+            .is_ok());
+        assert!(state
+            .add_request(titled_song("a", "Something else"), 0.15, None)
+            .is_err());
+        assert_eq!(state.requests.len(), 2);
+    }
+
+    fn requested_by(id: &str, nick: &str) -> SongbookSong {
+        SongbookSong {
+            queued_by: Some(nick.to_string()),
+            ..song(id)
+        }
+    }
+
+    #[tokio::test]
+    async fn add_request_accepts_requests_up_to_the_per_user_limit() {
+        let mut state = state_with_requests(&[]);
+
+        assert!(state
+            .add_request(requested_by("a", "nick"), 0.15, Some(2))
+            .is_ok());
+        assert!(state
+            .add_request(requested_by("b", "nick"), 0.15, Some(2))
+            .is_ok());
+        assert!(state
+            .add_request(requested_by("c", "other"), 0.15, Some(2))
+            .is_ok());
+        assert_eq!(ids(&state.requests), ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn add_request_rejects_requests_over_the_per_user_limit() {
+        let mut state = state_with_requests(&[]);
+        state.requests.push(requested_by("a", "nick"));
+        state.requests.push(requested_by("b", "nick"));
+
+        let err = state
+            .add_request(requested_by("c", "nick"), 0.15, Some(2))
+            .unwrap_err();
+        assert!(err.to_string().contains("already has 2 pending requests"));
+
+        // Duplicates are rejected as such before the limit is checked
+        let err = state
+            .add_request(requested_by("a", "nick"), 0.15, Some(2))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Song already requested");
+
+        assert!(state
+            .add_request(requested_by("c", "nick"), 0.15, None)
+            .is_ok());
+        assert_eq!(ids(&state.requests), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn import_of_export_round_trips() {
+        let mut state = state_with_requests(&["a", "b"]);
+        state.first_songs.push_back(song("first"));
+        state.backup.push(song("backup"));
+        state.mode = Mode::Singing;
+
+        let imported = SongleaderState::import(state.export()).unwrap();
+
+        assert_eq!(imported.export(), state.export());
+    }
+
+    #[tokio::test]
+    async fn swap_requests_swaps_positions() {
+        let mut state = state_with_requests(&["a", "b", "c"]);
+
+        state
+            .swap_requests("a".to_string(), "c".to_string())
+            .unwrap();
+
+        assert_eq!(ids(&state.requests), ["c", "b", "a"]);
+    }
+
+    #[tokio::test]
+    async fn swap_requests_fails_for_unknown_ids() {
+        let mut state = state_with_requests(&["a", "b"]);
+
+        assert!(state
+            .swap_requests("a".to_string(), "x".to_string())
+            .is_err());
+        assert!(state
+            .swap_requests("x".to_string(), "y".to_string())
+            .is_err());
+        assert_eq!(ids(&state.requests), ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn request_swapped_with_prioritized_song_is_sung_in_its_place() {
+        let mut state = state_with_requests(&["a", "b"]);
+        state.first_songs.extend([song("x"), song("y")]);
+
+        state
+            .swap_requests("b".to_string(), "x".to_string())
+            .unwrap();
+
+        assert_eq!(ids(&state.first_songs), ["b", "y"]);
+        assert_eq!(ids(&state.requests), ["a", "x"]);
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("b".into()));
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("y".into()));
+    }
+
+    #[tokio::test]
+    async fn swap_requests_swaps_prioritized_songs() {
+        let mut state = state_with_requests(&[]);
+        state.first_songs.extend([song("x"), song("y")]);
+
+        state
+            .swap_requests("y".to_string(), "x".to_string())
+            .unwrap();
+
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("y".into()));
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("x".into()));
+    }
+
+    #[tokio::test]
+    async fn reordered_requests_are_sung_in_the_new_order() {
+        let mut state = state_with_requests(&["a", "b", "c"]);
+        state.first_songs.push_back(song("first"));
+
+        state
+            .reorder_requests(vec!["c".into(), "a".into(), "b".into()])
+            .unwrap();
+
+        assert!(state.requests.is_empty());
+        assert_eq!(ids(&state.first_songs), ["first", "c", "a", "b"]);
+        assert_eq!(
+            state.pop_next_song().map(|song| song.id),
+            Some("first".into())
+        );
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("c".into()));
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("a".into()));
+    }
+
+    #[tokio::test]
+    async fn reorder_requests_rejects_missing_or_unknown_ids() {
+        let mut state = state_with_requests(&["a", "b", "c"]);
+
+        assert!(state
+            .reorder_requests(vec!["c".into(), "a".into()])
+            .is_err());
+        assert!(state
+            .reorder_requests(vec!["c".into(), "a".into(), "b".into(), "x".into()])
+            .is_err());
+        assert!(state
+            .reorder_requests(vec!["c".into(), "a".into(), "a".into()])
+            .is_err());
+        assert_eq!(ids(&state.requests), ["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn promoted_request_is_sung_next() {
+        let mut state = state_with_requests(&["a", "b"]);
+        state.first_songs.push_back(song("first"));
+
+        let promoted = state.promote_next_request().unwrap();
+
+        assert_eq!(promoted.id, "a");
+        assert_eq!(ids(&state.first_songs), ["a", "first"]);
+        assert_eq!(ids(&state.requests), ["b"]);
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("a".into()));
+    }
+
+    #[tokio::test]
+    async fn promote_next_request_fails_without_requests() {
+        let mut state = state_with_requests(&[]);
+
+        assert!(state.promote_next_request().is_err());
+    }
+
+    fn songleader_with_state(bus: EventBus, state: SongleaderState) -> Songleader {
+        let config = crate::config::example();
+
+        Songleader {
+            state,
+            bus,
+            tempo_deadline: Duration::from_secs(config.songleader.tempo_deadline_secs),
+            config,
+            pending_music_pause: None,
+            requests_open_override: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn enter_bingo_mode_announces_next_song() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut state = state_with_requests(&[]);
+        state.requests.push(titled_song("helan-gar", "Helan går"));
+        let mut songleader = songleader_with_state(bus, state);
+
+        songleader.enter_bingo_mode();
+
+        assert!(matches!(
+            &songleader.state.mode,
+            Mode::Bingo { song, .. } if song.id == "helan-gar"
+        ));
+
+        let events = captured.lock().unwrap();
+        let speech: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::TextToSpeech(TextToSpeechAction::Speak { text, .. }) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        let messages: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::Irc(IrcAction::SendMsg(msg)) => Some(msg.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(speech, ["Nästa sång kommer nu... Helan går"]);
+        assert!(messages
+            .iter()
+            .any(|msg| msg.starts_with("Next song coming up: Helan går")));
+        assert_eq!(messages.last(), Some(&"Type bingo when you have found it!"));
+    }
+
+    #[tokio::test]
+    async fn enter_bingo_mode_without_songs_returns_to_tempo() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut songleader = songleader_with_state(bus, state_with_requests(&[]));
+
+        songleader.enter_bingo_mode();
+
+        assert!(matches!(songleader.state.mode, Mode::Tempo { .. }));
+        assert!(captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg.starts_with("No songs found")
+        )));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn vote_thresholds_are_read_from_config() {
+        let (bus, _) = EventBus::new_capturing();
+        let mut config = crate::config::example();
+        config.songleader.num_tempo_nicks = 2;
+        config.songleader.num_bingo_nicks = 2;
+
+        let mut songleader = songleader_with_state(bus.clone(), state_with_requests(&["a"]));
+        songleader.config = config.clone();
+        songleader.enter_tempo_mode();
+        let songleader = Arc::new(RwLock::new(songleader));
+
+        let handle =
+            |action| handle_incoming_event(bus.clone(), config.clone(), songleader.clone(), action);
+        let describe = || async {
+            let songleader = songleader.read().await;
+            songleader.state.mode.describe(&config.songleader)
+        };
+
+        handle(SongleaderAction::Tempo { nick: "a".into() }).await;
+        assert_eq!(describe().await, "Tempo (1/2)");
+        handle(SongleaderAction::Tempo { nick: "b".into() }).await;
+        assert_eq!(describe().await, "Bingo (0/2)");
+
+        handle(SongleaderAction::Bingo { nick: "a".into() }).await;
+        assert_eq!(describe().await, "Bingo (1/2)");
+        handle(SongleaderAction::Bingo { nick: "b".into() }).await;
+        assert_eq!(describe().await, "Singing");
+    }
+
+    #[tokio::test]
+    async fn panic_while_entering_bingo_mode_restarts_tempo() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut songleader = songleader_with_state(bus, state_with_requests(&["a"]));
+        let init_t = Instant::now() - Duration::from_secs(60);
+        songleader.state.mode = Mode::Tempo {
+            init_t,
+            nicks: nicks(2),
+        };
+
+        enter_bingo_mode_or_restart_tempo(&mut songleader, |songleader| {
+            songleader.state.pop_next_song();
+            panic!("Picking the next song failed");
+        });
+
+        assert!(matches!(
+            &songleader.state.mode,
+            Mode::Tempo { init_t: restarted_t, nicks } if *restarted_t > init_t && nicks.is_empty()
+        ));
+        assert!(captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg.contains("restarting tempo")
+        )));
+
+        // The next timeout works as usual
+        songleader.state.requests.push(song("b"));
+        enter_bingo_mode_or_restart_tempo(&mut songleader, Songleader::enter_bingo_mode);
+        assert!(matches!(&songleader.state.mode, Mode::Bingo { song, .. } if song.id == "b"));
+    }
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn window_without_bounds_is_always_open() {
+        assert!(is_within_window(time(3), None, None));
+    }
+
+    #[test]
+    fn window_with_one_bound_is_open_on_the_other_side() {
+        assert!(!is_within_window(time(17), Some(time(18)), None));
+        assert!(is_within_window(time(18), Some(time(18)), None));
+        assert!(is_within_window(time(22), None, Some(time(23))));
+        assert!(!is_within_window(time(23), None, Some(time(23))));
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let (open_at, close_at) = (Some(time(18)), Some(time(23)));
+
+        assert!(!is_within_window(time(17), open_at, close_at));
+        assert!(is_within_window(time(18), open_at, close_at));
+        assert!(is_within_window(time(22), open_at, close_at));
+        assert!(!is_within_window(time(23), open_at, close_at));
+    }
+
+    #[test]
+    fn window_closing_before_it_opens_spans_midnight() {
+        let (open_at, close_at) = (Some(time(18)), Some(time(2)));
+
+        assert!(is_within_window(time(23), open_at, close_at));
+        assert!(is_within_window(time(1), open_at, close_at));
+        assert!(!is_within_window(time(2), open_at, close_at));
+        assert!(!is_within_window(time(12), open_at, close_at));
+    }
+
+    #[tokio::test]
+    async fn sung_pinned_song_forgets_its_position() {
+        let mut state = state_with_requests(&["a", "b", "c"]);
+        state.pin_songs(vec!["a".into()]);
+
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("a".into()));
+        assert!(state.pinned_positions.is_empty());
+
+        // Requested and prioritized again, so unpinning has nothing to restore
+        state.requests.push(song("a"));
+        state.move_request_to_first("a".into()).unwrap();
+        state.unpin_songs(vec!["a".into()]);
+        assert_eq!(ids(&state.requests), ["b", "c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn demoted_song_is_no_longer_prioritized() {
+        let mut state = state_with_requests(&["a"]);
+        state.first_songs.extend([song("x"), song("y")]);
+
+        let demoted = state.demote_first_song().unwrap();
+
+        assert_eq!(demoted.id, "x");
+        assert_eq!(ids(&state.requests), ["x", "a"]);
+        assert_eq!(state.pop_next_song().map(|song| song.id), Some("y".into()));
+        assert!(state.first_songs.is_empty());
+    }
+
+    fn nicks(n: usize) -> HashSet<String> {
+        (0..n).map(|i| format!("nick{i}")).collect()
+    }
+
+    #[test]
+    fn tempo_deadline_is_reduced_per_nick() {
+        let init_t = Instant::now();
+        let deadline = tempo_deadline(init_t, &nicks(2), 420 * SECOND, 60 * SECOND);
+
+        assert_eq!(deadline, init_t + 300 * SECOND);
+    }
+
+    #[test]
+    fn tempo_deadline_does_not_overflow() {
+        let init_t = Instant::now();
+
+        let deadline = tempo_deadline(init_t, &nicks(3), 420 * SECOND, Duration::MAX);
+        assert_eq!(deadline, init_t);
+
+        let deadline = tempo_deadline(init_t, &nicks(0), Duration::MAX, 60 * SECOND);
+        assert!(deadline > init_t);
+    }
+
+    #[test]
+    fn deduplicate_keeps_the_copy_in_the_highest_priority_queue() {
+        let mut state = SongleaderState {
+            first_songs: [titled_song("a", "first"), song("b")].into(),
+            requests: vec![titled_song("a", "request"), song("c"), song("b"), song("c")],
+            backup: vec![song("c"), song("d"), titled_song("a", "backup")],
+            ..Default::default()
+        };
+        state.pinned_positions.insert("a".into(), 0);
+
+        let mut removed = state.deduplicate_across_queues();
+        removed.sort();
+
+        assert_eq!(removed, ["a", "a", "b", "c", "c"]);
+        assert_eq!(ids(&state.first_songs), ["a", "b"]);
+        assert_eq!(state.first_songs[0].title.as_deref(), Some("first"));
+        assert_eq!(ids(&state.requests), ["c"]);
+        assert_eq!(ids(&state.backup), ["d"]);
+        assert!(state.pinned_positions.contains_key("a"));
+    }
+
+    #[test]
+    fn deduplicate_without_duplicates_changes_nothing() {
+        let mut state = state_with_requests(&["a", "b"]);
+        state.backup.push(song("c"));
+
+        assert!(state.deduplicate_across_queues().is_empty());
+        assert_eq!(ids(&state.requests), ["a", "b"]);
+        assert_eq!(ids(&state.backup), ["c"]);
+    }
+
+    #[test]
+    fn request_count_by_user_counts_only_their_requests() {
+        let mut state = state_with_requests(&["anonymous"]);
+        state.requests.extend([
+            requested_by("a", "nick"),
+            requested_by("b", "other"),
+            requested_by("c", "nick"),
+        ]);
+        state.first_songs.push_back(requested_by("d", "nick"));
+
+        assert_eq!(state.request_count_by_user("nick"), 2);
+        assert_eq!(state.request_count_by_user("other"), 1);
+        assert_eq!(state.request_count_by_user("nobody"), 0);
+    }
+
+    #[test]
+    fn get_song_by_id_searches_queues_in_order() {
+        let state = SongleaderState {
+            first_songs: [titled_song("a", "first")].into(),
+            requests: vec![titled_song("a", "request"), titled_song("b", "request")],
+            backup: vec![titled_song("b", "backup"), titled_song("c", "backup")],
+            ..Default::default()
+        };
+
+        let title = |id| {
+            state
+                .get_song_by_id(id)
+                .and_then(|song| song.title.as_deref())
+        };
+
+        assert_eq!(title("a"), Some("first"));
+        assert_eq!(title("b"), Some("request"));
+        assert_eq!(title("c"), Some("backup"));
+        assert!(state.get_song_by_id("unknown").is_none());
+    }
+
+    /// Handles `action` like the bus event loop would
+    async fn handle(songleader: &Arc<RwLock<Songleader>>, action: SongleaderAction) {
+        let (bus, config) = {
+            let songleader = songleader.read().await;
+            (songleader.bus.clone(), songleader.config.clone())
+        };
+
+        handle_incoming_event(bus, config, songleader.clone(), action).await;
+    }
+
+    #[tokio::test]
+    async fn removed_nick_no_longer_counts_towards_tempo_or_bingo() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut state = state_with_requests(&[]);
+        state.requests.push(requested_by("a", "nick1"));
+        let init_t = Instant::now();
+        state.mode = Mode::Tempo {
+            init_t,
+            nicks: nicks(2),
+        };
+        let songleader = Arc::new(RwLock::new(songleader_with_state(bus, state)));
+
+        handle(
+            &songleader,
+            SongleaderAction::RemoveNick {
+                nick: "nick1".into(),
+            },
+        )
+        .await;
+
+        assert_eq!(
+            songleader.read().await.state.mode,
+            Mode::Tempo {
+                init_t,
+                nicks: HashSet::from(["nick0".to_string()])
+            }
+        );
+        assert!(captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg.starts_with("Goodbye nick1! Your 1 requested")
+        )));
+
+        let bingo = Mode::Bingo {
+            nicks: nicks(1),
+            song: song("b"),
+        };
+        songleader.write().await.state.mode = bingo;
+        handle(
+            &songleader,
+            SongleaderAction::RemoveNick {
+                nick: "nick0".into(),
+            },
+        )
+        .await;
+
+        assert_eq!(
+            songleader.read().await.state.mode,
+            Mode::Bingo {
+                nicks: HashSet::new(),
+                song: song("b")
+            }
+        );
+        assert!(!captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Songleader(SongleaderAction::ModeChanged { .. })
+        )));
+    }
+
+    #[tokio::test]
+    async fn last_skål_ends_the_party_with_auto_end_on_empty() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut state = state_with_requests(&["a"]);
+        state.mode = Mode::Singing;
+        let mut songleader = songleader_with_state(bus, state);
+        songleader.config.songleader.auto_end_on_empty = true;
+        let songleader = Arc::new(RwLock::new(songleader));
+
+        // Songs are left, so the party goes on
+        handle(&songleader, SongleaderAction::Skål).await;
+        assert!(matches!(
+            songleader.read().await.state.mode,
+            Mode::Tempo { .. }
+        ));
+
+        {
+            let mut songleader = songleader.write().await;
+            songleader.state.requests.clear();
+            songleader.state.mode = Mode::Singing;
+        }
+        handle(&songleader, SongleaderAction::Skål).await;
+
+        assert_eq!(songleader.read().await.state.mode, Mode::Inactive);
+        assert!(captured.lock().unwrap().iter().any(|event| matches!(
+            event,
+            Event::Irc(IrcAction::SendMsg(msg)) if msg.contains("Party is over")
+        )));
+    }
+
+    #[tokio::test]
+    async fn last_skål_returns_to_tempo_without_auto_end_on_empty() {
+        let (bus, _) = EventBus::new_capturing();
+        let mut state = state_with_requests(&[]);
+        state.mode = Mode::Singing;
+        let mut songleader = songleader_with_state(bus, state);
+        songleader.config.songleader.auto_end_on_empty = false;
+        let songleader = Arc::new(RwLock::new(songleader));
+
+        handle(&songleader, SongleaderAction::Skål).await;
+
+        assert!(matches!(
+            songleader.read().await.state.mode,
+            Mode::Tempo { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn every_mode_change_is_announced_on_the_bus() {
+        let (bus, captured) = EventBus::new_capturing();
+        let mut songleader = songleader_with_state(bus, state_with_requests(&["a"]));
+
+        songleader.enter_tempo_mode();
+        songleader.enter_bingo_mode();
+        songleader.enter_inactive_mode();
+
+        let changes: Vec<(&str, &str)> = captured
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Songleader(SongleaderAction::ModeChanged { old_mode, new_mode }) => {
+                    Some((old_mode.name(), new_mode.name()))
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            changes,
+            [
+                ("inactive", "tempo"),
+                ("tempo", "bingo"),
+                ("bingo", "inactive")
+            ]
+        );
+    }
 }
@@ -1,13 +1,18 @@
 #![allow(non_upper_case_globals)]
 use crate::{
     buffer::PlaybackBuffer,
+    config::{Config, TtsVoiceConfig},
     event::{Event, EventBus},
     mixer::{MixerAction, MixerInput, Sample},
 };
 use serde::Deserialize;
-use std::sync::Arc;
+use std::sync::{atomic::Ordering, Arc};
 use tokio::sync::{mpsc, Mutex};
 
+/// Fraction of the normal music volume to duck to while speaking low
+/// priority speech, which is less disruptive than system announcements
+const LOW_PRIO_DUCK_FRACTION: f64 = 0.7;
+
 #[derive(Clone, Debug, Deserialize, Default, PartialEq)]
 pub enum Priority {
     #[default]
@@ -17,87 +22,209 @@ pub enum Priority {
 
 #[derive(Clone, Debug)]
 pub enum TextToSpeechAction {
-    Speak { text: String, prio: Priority },
+    Speak {
+        text: String,
+        prio: Priority,
+        /// Overrides the default voice if set
+        voice: Option<TtsVoiceConfig>,
+    },
+    /// Cancels any ongoing and pending speech and speaks with high priority
+    SpeakInterrupt {
+        text: String,
+    },
+    /// Cancels any ongoing and pending speech without speaking anything
+    ClearQueue,
     AllowLowPrio,
     DisallowLowPrio,
 }
 
-pub fn init(bus: &EventBus) -> MixerInput {
+/// Truncates `text` to at most `max_len` characters, marking it as truncated
+pub fn truncate_text(text: &str, max_len: usize) -> String {
+    if text.chars().count() <= max_len {
+        return text.to_string();
+    }
+
+    let truncated: String = text.chars().take(max_len).collect();
+    format!("{truncated} [truncated]")
+}
+
+/// Synthesized speech is buffered separately by priority, so that a system
+/// announcement only cuts off other system announcements and not speech
+/// requested by users
+#[derive(Clone, Default)]
+struct SpeechBuffers {
+    /// Buffer for [Priority::High] speech
+    system: Arc<Mutex<PlaybackBuffer>>,
+
+    /// Buffer for [Priority::Low] speech
+    user: Arc<Mutex<PlaybackBuffer>>,
+}
+
+impl SpeechBuffers {
+    fn for_prio(&self, prio: &Priority) -> &Arc<Mutex<PlaybackBuffer>> {
+        match prio {
+            Priority::High => &self.system,
+            Priority::Low => &self.user,
+        }
+    }
+
+    /// Returns the next system sample, or the next user sample if there is
+    /// no system speech, along with the priority of the speech
+    async fn next_sample(&self) -> Option<(Sample, Priority)> {
+        let sample = self.system.lock().await.next_sample();
+
+        match sample {
+            Some(sample) => Some((sample, Priority::High)),
+            None => {
+                let sample = self.user.lock().await.next_sample();
+                sample.map(|sample| (sample, Priority::Low))
+            }
+        }
+    }
+}
+
+pub fn init(bus: &EventBus, config: &Config) -> MixerInput {
     let (tx, rx) = mpsc::channel(128);
-    let playback_buf = Arc::new(Mutex::new(PlaybackBuffer::default()));
+    let buffers = SpeechBuffers::default();
 
-    start_speak_event_loop(bus.clone(), playback_buf.clone());
-    start_emit_sample_loop(bus.clone(), tx, playback_buf);
+    start_interrupt_event_loop(bus.clone(), buffers.clone());
+    start_speak_event_loop(
+        bus.clone(),
+        config.tts.tts_admin_max_text_length,
+        buffers.clone(),
+    );
+    start_emit_sample_loop(bus.clone(), tx, buffers);
 
     rx
 }
 
-fn start_speak_event_loop(bus: EventBus, playback_buf: Arc<Mutex<PlaybackBuffer>>) {
+/// Handles interrupts separately from [start_speak_event_loop], which may be
+/// busy synthesizing when the interrupt arrives
+fn start_interrupt_event_loop(bus: EventBus, buffers: SpeechBuffers) {
     tokio::spawn(async move {
-        // Check for any new events on the bus
         let mut bus = bus.subscribe();
 
         loop {
-            let event = bus.recv().await;
+            let Ok(event) = bus.recv_or_lagged().await else {
+                espeakng_sys_example::reset_pending_interrupts();
+                continue;
+            };
+
+            let clear_queue = match event {
+                Event::TextToSpeech(TextToSpeechAction::SpeakInterrupt { .. }) => false,
+                Event::TextToSpeech(TextToSpeechAction::ClearQueue) => true,
+                _ => continue,
+            };
+
+            // Abort ongoing and pending synthesis until the interrupting
+            // event is reached by the speak event loop. If the speak event
+            // loop got here first it has already taken care of this.
+            let pending = espeakng_sys_example::PENDING_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
 
-            if let Event::TextToSpeech(TextToSpeechAction::Speak { text, prio }) = event {
-                let spoken =
-                    tokio::task::spawn_blocking(move || espeakng_sys_example::speak(&text)).await;
+            if pending >= 0 {
+                for playback_buf in [&buffers.system, &buffers.user] {
+                    let mut playback_buf = playback_buf.lock().await;
 
-                let spoken = match spoken {
-                    Ok(spoken) => spoken,
-                    Err(e) => {
-                        error!("Error while calling espeakng: {:?}", e);
-                        continue;
+                    if clear_queue {
+                        playback_buf.discard_remaining();
+                    } else {
+                        playback_buf.clear();
                     }
-                };
+                }
+            }
+        }
+    });
+}
+
+fn start_speak_event_loop(bus: EventBus, max_text_length: usize, buffers: SpeechBuffers) {
+    tokio::spawn(async move {
+        // Check for any new events on the bus
+        let mut bus = bus.subscribe();
+
+        loop {
+            let Ok(event) = bus.recv_or_lagged().await else {
+                espeakng_sys_example::reset_pending_interrupts();
+                continue;
+            };
 
-                let mut playback_buf = playback_buf.lock().await;
-                if prio == Priority::High {
-                    playback_buf.clear();
+            let (text, prio, voice) = match event {
+                Event::TextToSpeech(TextToSpeechAction::Speak { text, prio, voice }) => {
+                    (text, prio, voice)
                 }
+                Event::TextToSpeech(TextToSpeechAction::SpeakInterrupt { text }) => {
+                    espeakng_sys_example::PENDING_INTERRUPTS.fetch_sub(1, Ordering::SeqCst);
+                    (text, Priority::High, None)
+                }
+                Event::TextToSpeech(TextToSpeechAction::ClearQueue) => {
+                    espeakng_sys_example::PENDING_INTERRUPTS.fetch_sub(1, Ordering::SeqCst);
+                    continue;
+                }
+                _ => continue,
+            };
 
-                // Add some silence before the sample
-                let mut audio = vec![0; 5000];
+            // Last resort guard against very long texts blocking the TTS
+            let text = truncate_text(&text, max_text_length);
 
-                audio.extend(spoken.wav);
+            let spoken = tokio::task::spawn_blocking(move || {
+                espeakng_sys_example::speak(&text, voice.as_ref())
+            })
+            .await;
 
-                // Add some silence after the sample
-                audio.extend(vec![0; 5000]);
+            let spoken = match spoken {
+                Ok(spoken) => spoken,
+                Err(e) => {
+                    error!("Error while calling espeakng: {:?}", e);
+                    continue;
+                }
+            };
 
-                let audio: Vec<Sample> = audio.into_iter().map(|sample| (sample, sample)).collect();
+            // Synthesis was cancelled by an interrupt
+            if spoken.wav.is_empty() {
+                continue;
+            }
 
-                playback_buf.push_samples(audio);
+            let mut playback_buf = buffers.for_prio(&prio).lock().await;
+            if prio == Priority::High {
+                playback_buf.clear();
             }
+
+            // Add some silence before the sample
+            let mut audio = vec![0; 5000];
+
+            audio.extend(spoken.wav);
+
+            // Add some silence after the sample
+            audio.extend(vec![0; 5000]);
+
+            let audio: Vec<Sample> = audio.into_iter().map(|sample| (sample, sample)).collect();
+
+            playback_buf.push_samples(audio);
         }
     });
 }
 
-fn start_emit_sample_loop(
-    bus: EventBus,
-    tx: mpsc::Sender<Sample>,
-    playback_buf: Arc<Mutex<PlaybackBuffer>>,
-) {
+fn start_emit_sample_loop(bus: EventBus, tx: mpsc::Sender<Sample>, buffers: SpeechBuffers) {
     tokio::spawn(async move {
-        let mut speaking = false;
+        // Priority of the speech currently being played
+        let mut speaking = None;
 
         loop {
-            let was_speaking = speaking;
-
-            let sample = {
-                let mut playback_buf = playback_buf.lock().await;
-                let sample = playback_buf.next_sample();
-
-                speaking = sample.is_some();
+            let was_speaking = speaking.clone();
 
-                sample.unwrap_or_default()
-            };
+            let sample = buffers.next_sample().await;
+            speaking = sample.as_ref().map(|(_, prio)| prio.clone());
+            let sample = sample.map(|(sample, _)| sample).unwrap_or_default();
 
             if speaking != was_speaking {
-                if speaking {
-                    bus.send(Event::Mixer(MixerAction::DuckSecondaryChannels))
-                } else {
-                    bus.send(Event::Mixer(MixerAction::UnduckSecondaryChannels))
+                match speaking {
+                    // System announcements duck music to the configured ducked volume
+                    Some(Priority::High) => {
+                        bus.send(Event::Mixer(MixerAction::DuckSecondaryChannels))
+                    }
+                    Some(Priority::Low) => bus.send(Event::Mixer(
+                        MixerAction::DuckSecondaryChannelsTo(LOW_PRIO_DUCK_FRACTION),
+                    )),
+                    None => bus.send(Event::Mixer(MixerAction::UnduckSecondaryChannels)),
                 }
             }
 
@@ -113,11 +240,13 @@ fn start_emit_sample_loop(
 
 // https://github.com/Better-Player/espeakng-sys/tree/9aeadd42772da076c1a1d5fbcd6384b8c9d56bba#example
 mod espeakng_sys_example {
+    use crate::config::TtsVoiceConfig;
     use espeakng_sys::*;
     use lazy_static::lazy_static;
     use std::cell::Cell;
     use std::ffi::{c_void, CString};
     use std::os::raw::{c_char, c_int, c_short};
+    use std::sync::atomic::{AtomicIsize, Ordering};
     use std::sync::{Mutex, MutexGuard};
 
     /// The name of the voice to use
@@ -127,6 +256,19 @@ mod espeakng_sys_example {
     /// Options to set for espeak-ng
     const OPTIONS: i32 = 0;
 
+    /// Number of interrupts that the speak event loop has not reached yet.
+    /// Ongoing synthesis is aborted by [synth_callback] while this is
+    /// positive. May briefly go negative if the speak event loop sees the
+    /// interrupt before the interrupt event loop does.
+    pub static PENDING_INTERRUPTS: AtomicIsize = AtomicIsize::new(0);
+
+    /// Forgets all pending interrupts. Called when either event loop lags
+    /// behind the bus, since skipped interrupt events would otherwise leave
+    /// [PENDING_INTERRUPTS] unbalanced and keep aborting all speech.
+    pub fn reset_pending_interrupts() {
+        PENDING_INTERRUPTS.store(0, Ordering::SeqCst);
+    }
+
     lazy_static! {
         /// The complete audio provided by the callback
         static ref AUDIO_RETURN: Mutex<Cell<Vec<i16>>> = Mutex::new(Cell::new(Vec::default()));
@@ -143,8 +285,8 @@ mod espeakng_sys_example {
         pub sample_rate: i32,
     }
 
-    /// Perform Text-To-Speech
-    pub fn speak(text: &str) -> Spoken {
+    /// Perform Text-To-Speech, optionally overriding the default voice
+    pub fn speak(text: &str, voice: Option<&TtsVoiceConfig>) -> Spoken {
         let output: espeak_AUDIO_OUTPUT = espeak_AUDIO_OUTPUT_AUDIO_OUTPUT_RETRIEVAL;
 
         AUDIO_RETURN.plock().set(Vec::default());
@@ -152,7 +294,10 @@ mod espeakng_sys_example {
 
         // The directory which contains the espeak-ng-data directory, or NULL for the default location.
         let path: *const c_char = std::ptr::null();
-        let voice_name_cstr = CString::new(VOICE_NAME).expect("Failed to convert &str to CString");
+        let voice_name = voice
+            .and_then(|voice| voice.voice.as_deref())
+            .unwrap_or(VOICE_NAME);
+        let voice_name_cstr = CString::new(voice_name).expect("Failed to convert &str to CString");
         let voice_name = voice_name_cstr.as_ptr();
 
         // Returns: sample rate in Hz, or -1 (EE_INTERNAL_ERROR).
@@ -163,6 +308,19 @@ mod espeakng_sys_example {
             espeak_SetSynthCallback(Some(synth_callback))
         }
 
+        // Parameters are reset by espeak_Initialize, so only overrides need to be set
+        if let Some(rate) = voice.and_then(|voice| voice.rate) {
+            unsafe {
+                espeak_SetParameter(espeak_PARAMETER_espeakRATE, rate as c_int, 0);
+            }
+        }
+
+        if let Some(pitch) = voice.and_then(|voice| voice.pitch) {
+            unsafe {
+                espeak_SetParameter(espeak_PARAMETER_espeakPITCH, pitch as c_int, 0);
+            }
+        }
+
         let text_cstr = CString::new(text).expect("Failed to convert &str to CString");
 
         let position = 0u32;
@@ -220,11 +378,17 @@ mod espeakng_sys_example {
     /// events is terminated by an event of type = 0.
     ///
     /// Callback returns: 0=continue synthesis,  1=abort synthesis.
+    ///
+    /// Aborts while there are [PENDING_INTERRUPTS].
     unsafe extern "C" fn synth_callback(
         wav: *mut c_short,
         sample_count: c_int,
         events: *mut espeak_EVENT,
     ) -> c_int {
+        if PENDING_INTERRUPTS.load(Ordering::SeqCst) > 0 {
+            return 1;
+        }
+
         // Calculate the length of the events array
         let mut events_copy = events;
         let mut elem_count = 0;
@@ -283,3 +447,26 @@ mod espeakng_sys_example {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_text_keeps_short_text() {
+        assert_eq!(truncate_text("hello", 5), "hello");
+        assert_eq!(truncate_text("", 0), "");
+    }
+
+    #[test]
+    fn truncate_text_marks_truncated_text() {
+        assert_eq!(truncate_text("hello world", 5), "hello [truncated]");
+        assert_eq!(truncate_text("hello", 0), " [truncated]");
+    }
+
+    #[test]
+    fn truncate_text_counts_characters_not_bytes() {
+        assert_eq!(truncate_text("åäö", 3), "åäö");
+        assert_eq!(truncate_text("åäöå", 3), "åäö [truncated]");
+    }
+}
@@ -1,15 +1,21 @@
 use crate::{
     buffer::PlaybackBuffer,
+    config::{Config, YoutubeConfig},
     constants::SAMPLE_RATE,
     event::{Event, EventBus},
     irc::IrcAction,
+    metrics,
     mixer::{MixerInput, Sample},
+    persist,
     playback::PlaybackAction,
     youtube::get_yt_media_source_stream,
 };
 use anyhow::{Context, Result};
 use itertools::Itertools;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{fs::File, sync::Arc};
 use symphonia::core::audio::SampleBuffer;
 use symphonia::core::codecs::DecoderOptions;
@@ -17,28 +23,174 @@ use symphonia::core::formats::FormatOptions;
 use symphonia::core::io::MediaSourceStream;
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
+use symphonia::core::units::Time;
 use tokio::sync::{mpsc, oneshot, Mutex, RwLock};
 
 #[derive(Clone, Debug)]
 pub enum SymphoniaAction {
-    PlayFile { file_path: String },
-    PlayYtUrl { url: String },
+    PlayFile {
+        file_path: String,
+    },
+    PlayYtUrl {
+        /// Id of the song in the playback queue, reported back if playing
+        /// it fails
+        id: String,
+        url: String,
+        title: String,
+        trim: Option<(u64, u64)>,
+        skip_segments: Vec<(f64, f64)>,
+    },
     Stop,
     Pause,
     Resume,
+    /// Probes the duration of a song from its media metadata, replying with
+    /// [PlaybackAction::SetDuration]
+    GetDuration {
+        id: String,
+        url: String,
+    },
+    /// Writes the currently buffered audio to a WAV file in the configured
+    /// dump directory for debugging. A file name is generated if none is
+    /// given.
+    DumpBuffer {
+        file_name: Option<String>,
+    },
+    /// Jumps to a position in the current song, replying with
+    /// [PlaybackAction::Seeked] on success
+    Seek {
+        position_secs: u64,
+    },
+    /// Changes how long songs are crossfaded into each other
+    SetCrossfadeDuration {
+        ms: u64,
+    },
 }
 
-pub async fn init(bus: &EventBus) -> Result<MixerInput> {
+/// Longest crossfade that can be configured. Longer ones would end songs
+/// early by their whole length once decoding has finished.
+pub const MAX_CROSSFADE_DURATION_MS: u64 = 10_000;
+
+/// End of the previous song that is being faded out under the next one
+#[derive(Default)]
+struct FadeOut {
+    samples: VecDeque<Sample>,
+
+    /// Number of samples the fade started with
+    len: usize,
+}
+
+impl FadeOut {
+    fn start(&mut self, samples: Vec<Sample>) {
+        self.len = samples.len();
+        self.samples = samples.into();
+    }
+
+    /// Linearly blends the next faded out sample with `sample` from the next
+    /// song, returning `sample` as is once the fade is over
+    fn blend(&mut self, sample: Sample) -> Sample {
+        let Some(old) = self.samples.pop_front() else {
+            return sample;
+        };
+
+        let t = 1.0 - self.samples.len() as f64 / self.len as f64;
+        let mix = |old: i16, new: i16| ((1.0 - t) * old as f64 + t * new as f64) as i16;
+
+        (mix(old.0, sample.0), mix(old.1, sample.1))
+    }
+}
+
+/// Crossfade state shared between the decode and emit loops
+#[derive(Clone)]
+struct Crossfade {
+    duration_ms: Arc<AtomicU64>,
+    fade_out: Arc<Mutex<FadeOut>>,
+
+    /// Whether `fade_out` has samples left, so that the emit loop only locks
+    /// it during a fade
+    fading: Arc<AtomicBool>,
+}
+
+impl Crossfade {
+    fn new(duration_ms: u64) -> Self {
+        Self {
+            duration_ms: Arc::new(AtomicU64::new(duration_ms.min(MAX_CROSSFADE_DURATION_MS))),
+            fade_out: Default::default(),
+            fading: Default::default(),
+        }
+    }
+
+    /// Length of the crossfade in samples
+    fn num_samples(&self) -> usize {
+        (self.duration_ms.load(Ordering::Relaxed) * SAMPLE_RATE as u64 / 1000) as usize
+    }
+
+    /// Starts fading out the part of `playback_buf` that hasn't been played
+    /// yet, up to the crossfade length. Returns the number of samples taken
+    /// for the fade out.
+    async fn start_fade_out(&self, playback_buf: &PlaybackBuffer) -> usize {
+        let samples = playback_buf.upcoming_samples(self.num_samples());
+        let num_samples = samples.len();
+
+        let mut fade_out = self.fade_out.lock().await;
+        fade_out.start(samples);
+        self.fading.store(num_samples > 0, Ordering::Release);
+
+        num_samples
+    }
+
+    /// Blends `sample` with the song being faded out, if any
+    async fn blend(&self, sample: Sample) -> Sample {
+        if !self.fading.load(Ordering::Acquire) {
+            return sample;
+        }
+
+        let mut fade_out = self.fade_out.lock().await;
+        let sample = fade_out.blend(sample);
+
+        if fade_out.samples.is_empty() {
+            self.fading.store(false, Ordering::Release);
+        }
+
+        sample
+    }
+}
+
+pub async fn init(bus: &EventBus, config: &Config) -> Result<MixerInput> {
     let (tx, rx) = mpsc::channel(128);
     let playback_buf = Arc::new(Mutex::new(PlaybackBuffer::default()));
-
-    start_decode_event_loop(bus.clone(), playback_buf.clone());
-    start_emit_sample_loop(bus.clone(), tx, playback_buf);
+    let crossfade = Crossfade::new(config.playback.crossfade_duration_ms);
+
+    start_decode_event_loop(
+        bus.clone(),
+        config.youtube.clone(),
+        config.state.dump_dir(),
+        config.irc.irc_announce_buffering,
+        playback_buf.clone(),
+        crossfade.clone(),
+    );
+    start_emit_sample_loop(bus.clone(), tx, playback_buf, crossfade);
 
     Ok(rx)
 }
 
-fn start_decode_event_loop(bus: EventBus, playback_buf: Arc<Mutex<PlaybackBuffer>>) {
+/// Sends a playback status message to the channel if `announce` is set,
+/// otherwise only logs it
+fn announce(bus: &EventBus, announce: bool, msg: String) {
+    if announce {
+        bus.send(Event::Irc(IrcAction::SendMsg(msg)));
+    } else {
+        info!("{msg}");
+    }
+}
+
+fn start_decode_event_loop(
+    bus: EventBus,
+    youtube_config: YoutubeConfig,
+    dump_dir: PathBuf,
+    announce_buffering: bool,
+    playback_buf: Arc<Mutex<PlaybackBuffer>>,
+    crossfade: Crossfade,
+) {
     tokio::spawn(async move {
         // Check for any new events on the bus
         let mut bus_tx = bus.subscribe();
@@ -51,16 +203,98 @@ fn start_decode_event_loop(bus: EventBus, playback_buf: Arc<Mutex<PlaybackBuffer
                 let playback_buf = playback_buf.clone();
                 let cancel_decode_task_tx = cancel_decode_task_tx.clone();
                 let bus = bus.clone();
+                let youtube_config = youtube_config.clone();
+                let dump_dir = dump_dir.clone();
+                let crossfade = crossfade.clone();
 
                 tokio::spawn(async move {
-                    let result =
-                        handle_incoming_event(action, playback_buf, cancel_decode_task_tx).await;
+                    // Probing runs alongside playback and must not affect it
+                    if let SymphoniaAction::GetDuration { id, url } = action {
+                        let result = get_duration(url, &youtube_config).await;
+
+                        match result {
+                            Ok(duration) => {
+                                bus.send(Event::Playback(PlaybackAction::SetDuration {
+                                    id,
+                                    duration,
+                                }))
+                            }
+                            Err(e) => {
+                                let msg = format!("Error while probing duration of {id}: {e}");
+                                error!("{}", msg);
+                                bus.send(Event::Irc(IrcAction::SendMsg(msg)));
+                            }
+                        }
+
+                        return;
+                    }
 
-                    if let Err(e) = result {
-                        let msg = format!("Error during music playback: {}, pausing playback", e);
-                        error!("{}", msg);
+                    if let SymphoniaAction::DumpBuffer { file_name } = action {
+                        let file_name = file_name.unwrap_or_else(|| {
+                            let now = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map(|d| d.as_secs())
+                                .unwrap_or_default();
+                            format!("buffer-{now}.wav")
+                        });
+
+                        let Some(path) = persist::file_in_dir(&dump_dir, &file_name) else {
+                            bus.send(Event::Irc(IrcAction::SendMsg(format!(
+                                "Error: Invalid file name {file_name}"
+                            ))));
+                            return;
+                        };
+
+                        let wav = playback_buf.lock().await.to_wav_bytes(SAMPLE_RATE);
+
+                        let result = match tokio::fs::create_dir_all(&dump_dir).await {
+                            Ok(()) => tokio::fs::write(&path, wav).await,
+                            Err(e) => Err(e),
+                        };
+
+                        let path = path.display();
+                        let msg = match result {
+                            Ok(()) => format!("Dumped playback buffer to {path}"),
+                            Err(e) => format!("Error while dumping playback buffer to {path}: {e}"),
+                        };
                         bus.send(Event::Irc(IrcAction::SendMsg(msg)));
-                        bus.send(Event::Playback(PlaybackAction::Pause));
+
+                        return;
+                    }
+
+                    let song_id = match &action {
+                        SymphoniaAction::PlayYtUrl { id, .. } => Some(id.clone()),
+                        _ => None,
+                    };
+
+                    let result = handle_incoming_event(
+                        action,
+                        &bus,
+                        &youtube_config,
+                        playback_buf,
+                        cancel_decode_task_tx,
+                        &crossfade,
+                        announce_buffering,
+                    )
+                    .await;
+
+                    if let Err(e) = result {
+                        match song_id {
+                            Some(id) => {
+                                error!("Error playing song {id}: {e}");
+                                bus.send(Event::Playback(PlaybackAction::SongFailed {
+                                    id,
+                                    error: e.to_string(),
+                                }));
+                            }
+                            None => {
+                                let msg =
+                                    format!("Error during music playback: {}, pausing playback", e);
+                                error!("{}", msg);
+                                bus.send(Event::Irc(IrcAction::SendMsg(msg)));
+                                bus.send(Event::Playback(PlaybackAction::Pause));
+                            }
+                        }
                     }
                 });
             }
@@ -70,8 +304,12 @@ fn start_decode_event_loop(bus: EventBus, playback_buf: Arc<Mutex<PlaybackBuffer
 
 async fn handle_incoming_event(
     action: SymphoniaAction,
+    bus: &EventBus,
+    youtube_config: &YoutubeConfig,
     playback_buf: Arc<Mutex<PlaybackBuffer>>,
     cancel_decode_task_tx: Arc<RwLock<Option<oneshot::Sender<()>>>>,
+    crossfade: &Crossfade,
+    announce_buffering: bool,
 ) -> Result<()> {
     match &action {
         SymphoniaAction::PlayFile { .. } | SymphoniaAction::PlayYtUrl { .. } => {
@@ -90,36 +328,63 @@ async fn handle_incoming_event(
 
             {
                 let mut playback_buf = playback_buf.lock().await;
+                crossfade.start_fade_out(&playback_buf).await;
                 playback_buf.clear();
                 playback_buf.set_paused(false);
             }
 
-            let (mss, url) = match action {
+            let (mss, url, title, trim, skip_segments) = match action {
                 SymphoniaAction::PlayFile { file_path } => {
                     // Create a media source. Note that the MediaSource trait is automatically implemented for File,
                     // among other types.
                     let source = Box::new(File::open(Path::new(&file_path))?);
                     (
                         MediaSourceStream::new(source, Default::default()),
+                        file_path.clone(),
                         file_path,
+                        None,
+                        vec![],
                     )
                 }
-                SymphoniaAction::PlayYtUrl { url } => {
-                    (get_yt_media_source_stream(url.clone()).await?, url)
+                SymphoniaAction::PlayYtUrl {
+                    url,
+                    title,
+                    trim,
+                    skip_segments,
+                    ..
+                } => {
+                    announce(bus, announce_buffering, format!("Buffering {title}…"));
+
+                    let mss =
+                        get_yt_media_source_stream(url.clone(), youtube_config, Some(bus.clone()))
+                            .await?;
+
+                    (mss, url, title, trim, skip_segments)
                 }
                 _ => unreachable!(),
             };
 
+            let decode_start = Instant::now();
             let result = {
                 let playback_buf = playback_buf.clone();
-                tokio::task::spawn_blocking(|| {
-                    decode_source(mss, playback_buf, cancel_decode_task_rx)
+                let bus = bus.clone();
+                tokio::task::spawn_blocking(move || {
+                    decode_source(
+                        mss,
+                        trim,
+                        &skip_segments,
+                        playback_buf,
+                        cancel_decode_task_rx,
+                        || announce(&bus, announce_buffering, format!("Playing {title}")),
+                    )
                 })
                 .await??
             };
 
             match result {
                 DecoderResult::EndOfFile => {
+                    metrics::SYMPHONIA_DECODE_DURATION.observe_since(decode_start);
+
                     let mut playback_buf = playback_buf.lock().await;
                     playback_buf.set_eof(true);
                     info!("Finished decoding audio from {url}");
@@ -132,6 +397,10 @@ async fn handle_incoming_event(
         SymphoniaAction::Stop => {
             {
                 let mut playback_buf = playback_buf.lock().await;
+                let faded = crossfade.start_fade_out(&playback_buf).await;
+
+                // Don't play the faded out samples again when resuming
+                playback_buf.advance(faded);
                 playback_buf.set_paused(true);
             }
 
@@ -150,30 +419,106 @@ async fn handle_incoming_event(
             let mut playback_buf = playback_buf.lock().await;
             playback_buf.set_paused(false);
         }
+        SymphoniaAction::SetCrossfadeDuration { ms } => {
+            let ms = (*ms).min(MAX_CROSSFADE_DURATION_MS);
+            crossfade.duration_ms.store(ms, Ordering::Relaxed);
+            bus.send(Event::Irc(IrcAction::SendMsg(format!(
+                "Crossfade duration set to {ms} ms"
+            ))));
+        }
+        SymphoniaAction::Seek { position_secs } => {
+            // The media stream can't be seeked, but songs are usually decoded
+            // well ahead of playback, so seek within the decoded samples
+            let sample = usize::try_from(*position_secs)
+                .ok()
+                .and_then(|secs| secs.checked_mul(SAMPLE_RATE as usize));
+            let seeked = match sample {
+                Some(sample) => playback_buf.lock().await.seek(sample),
+                None => false,
+            };
+
+            if seeked {
+                bus.send(Event::Playback(PlaybackAction::Seeked {
+                    position_secs: *position_secs,
+                }));
+            } else {
+                bus.send(Event::Irc(IrcAction::SendMsg(format!(
+                    "Can't seek to {position_secs}s, that part of the song hasn't been buffered"
+                ))));
+            }
+        }
+        // Handled separately in start_decode_event_loop
+        SymphoniaAction::GetDuration { .. } | SymphoniaAction::DumpBuffer { .. } => {}
     }
 
     Ok(())
 }
 
+/// Fetches a YouTube media stream and probes its duration in seconds
+async fn get_duration(url: String, youtube_config: &YoutubeConfig) -> Result<u64> {
+    let mss = get_yt_media_source_stream(url, youtube_config, None).await?;
+
+    tokio::task::spawn_blocking(move || probe_duration(mss)).await?
+}
+
+/// Reads the duration of a media source from its metadata without decoding
+/// any audio
+fn probe_duration(mss: MediaSourceStream) -> Result<u64> {
+    let format_opts: FormatOptions = Default::default();
+    let metadata_opts: MetadataOptions = Default::default();
+
+    let probed =
+        symphonia::default::get_probe().format(&Hint::new(), mss, &format_opts, &metadata_opts)?;
+
+    let track = probed
+        .format
+        .default_track()
+        .context("Could not find any tracks in file")?;
+
+    let n_frames = track
+        .codec_params
+        .n_frames
+        .context("Media does not report its length")?;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .context("Media does not report its sample rate")?;
+
+    Ok(n_frames / sample_rate as u64)
+}
+
 fn start_emit_sample_loop(
     bus: EventBus,
     tx: mpsc::Sender<Sample>,
     playback_buf: Arc<Mutex<PlaybackBuffer>>,
+    crossfade: Crossfade,
 ) {
     tokio::spawn(async move {
         loop {
-            let (sample, decoder_hit_eof) = {
+            let (sample, end_of_song) = {
                 let mut playback_buf = playback_buf.lock().await;
-                (playback_buf.next_sample(), playback_buf.is_eof())
+                let sample = playback_buf.next_sample();
+
+                // Move on to the next song once only the crossfade is left of
+                // this one, so that the rest can be faded into the next song
+                let end_of_song = playback_buf.is_eof()
+                    && !playback_buf.is_paused()
+                    && (sample.is_none() || playback_buf.remaining() <= crossfade.num_samples());
+
+                if end_of_song {
+                    playback_buf.set_eof(false);
+                }
+
+                (sample, end_of_song)
             };
 
-            if sample.is_none() && decoder_hit_eof {
-                let mut playback_buf = playback_buf.lock().await;
-                playback_buf.clear();
+            if end_of_song {
                 bus.send(Event::Playback(PlaybackAction::EndOfSong))
             }
 
-            tx.send(sample.unwrap_or_default())
+            let sample = crossfade.blend(sample.unwrap_or_default()).await;
+
+            tx.send(sample)
                 .await
                 .expect("Expected mixer channel to never close");
         }
@@ -185,10 +530,17 @@ pub enum DecoderResult {
     Cancelled,
 }
 
+/// Decodes `mss` into `playback_buf` until the end of the source or until
+/// cancelled, leaving out `skip_segments` (start and end in seconds).
+/// `on_first_samples` is called once the first decoded samples have been
+/// written to the buffer.
 pub fn decode_source(
     mss: MediaSourceStream,
+    trim: Option<(u64, u64)>,
+    skip_segments: &[(f64, f64)],
     playback_buf: Arc<Mutex<PlaybackBuffer>>,
     mut cancel_decode_task_rx: oneshot::Receiver<()>,
+    on_first_samples: impl FnOnce(),
 ) -> Result<DecoderResult> {
     // Create a hint to help the format registry guess what format reader is appropriate. In this
     // example we'll leave it empty.
@@ -217,8 +569,43 @@ pub fn decode_source(
     // Store the track identifier, we'll use it to filter packets.
     let track_id = track.id;
 
+    // We stream the media URL directly instead of letting yt-dlp download
+    // only the trimmed segment, so skip packets outside the segment here.
+    let trim_ts = trim
+        .zip(track.codec_params.time_base)
+        .map(|((start, end), time_base)| {
+            (
+                time_base.calc_timestamp(Time::new(start, 0.0)),
+                time_base.calc_timestamp(Time::new(end, 0.0)),
+            )
+        });
+
+    // Same goes for --sponsorblock-remove
+    let skip_ts: Vec<(u64, u64)> = match track.codec_params.time_base {
+        Some(time_base) => skip_segments
+            .iter()
+            .map(|(start, end)| {
+                (
+                    time_base.calc_timestamp(Time::from(*start)),
+                    time_base.calc_timestamp(Time::from(*end)),
+                )
+            })
+            .collect(),
+        None => vec![],
+    };
+
+    // Without a time base, fall back to counting decoded samples
+    let trim_samples = match (trim, trim_ts) {
+        (Some((start, end)), None) => Some((
+            start as usize * SAMPLE_RATE as usize,
+            end as usize * SAMPLE_RATE as usize,
+        )),
+        _ => None,
+    };
+
     let mut sample_count = 0;
     let mut sample_buf = None;
+    let mut on_first_samples = Some(on_first_samples);
 
     loop {
         // Get the next packet from the format reader.
@@ -240,6 +627,23 @@ pub fn decode_source(
             continue;
         }
 
+        if let Some((start_ts, end_ts)) = trim_ts {
+            if packet.ts() < start_ts {
+                continue;
+            }
+
+            if packet.ts() >= end_ts {
+                return Ok(DecoderResult::EndOfFile);
+            }
+        }
+
+        if skip_ts
+            .iter()
+            .any(|(start_ts, end_ts)| (*start_ts..*end_ts).contains(&packet.ts()))
+        {
+            continue;
+        }
+
         // Decode the packet into audio samples
         let audio_buf = decoder.decode(&packet)?;
 
@@ -270,13 +674,27 @@ pub fn decode_source(
 
             // The samples may now be access via the `samples()` function.
             let samples = buf.samples();
+            let first_sample = sample_count;
             sample_count += samples.len() / 2;
             trace!(
                 "\rDecoded {:.2} seconds",
                 sample_count as f64 / SAMPLE_RATE as f64
             );
 
-            let samples: Vec<Sample> = samples.iter().copied().tuples().collect();
+            let mut samples: Vec<Sample> = samples.iter().copied().tuples().collect();
+
+            if let Some((start, end)) = trim_samples {
+                if first_sample >= end {
+                    return Ok(DecoderResult::EndOfFile);
+                }
+
+                samples.truncate(end - first_sample);
+                samples.drain(..start.saturating_sub(first_sample).min(samples.len()));
+
+                if samples.is_empty() {
+                    continue;
+                }
+            }
 
             // Bail if task has been cancelled
             if cancel_decode_task_rx.try_recv().is_ok() {
@@ -288,6 +706,65 @@ pub fn decode_source(
                 let mut playback_buf = playback_buf.blocking_lock();
                 playback_buf.push_samples(samples);
             }
+
+            if let Some(on_first_samples) = on_first_samples.take() {
+                on_first_samples();
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_fades_linearly_into_the_next_song() {
+        let mut fade_out = FadeOut::default();
+        fade_out.start(vec![(1000, -1000); 4]);
+
+        let blended: Vec<Sample> = (0..4).map(|_| fade_out.blend((2000, 0))).collect();
+
+        assert_eq!(
+            blended,
+            [(1250, -750), (1500, -500), (1750, -250), (2000, 0)]
+        );
+    }
+
+    #[test]
+    fn fade_out_into_silence_ends_silent() {
+        let mut fade_out = FadeOut::default();
+        fade_out.start(vec![(10000, 10000); 100]);
+
+        let blended: Vec<i16> = (0..100).map(|_| fade_out.blend((0, 0)).0).collect();
+
+        assert!(blended.windows(2).all(|pair| pair[0] > pair[1]));
+        assert_eq!(blended.last(), Some(&0));
+        assert_eq!(fade_out.blend((0, 0)), (0, 0));
+    }
+
+    #[test]
+    fn blend_without_fade_returns_sample() {
+        let mut fade_out = FadeOut::default();
+        assert_eq!(fade_out.blend((123, -123)), (123, -123));
+
+        fade_out.start(vec![]);
+        assert_eq!(fade_out.blend((123, -123)), (123, -123));
+    }
+
+    #[tokio::test]
+    async fn crossfade_only_blends_while_fading() {
+        let crossfade = Crossfade::new(1000);
+        assert_eq!(crossfade.blend((100, 100)).await, (100, 100));
+
+        let mut playback_buf = PlaybackBuffer::default();
+        playback_buf.push_samples([(1000, 1000), (1000, 1000)]);
+
+        assert_eq!(crossfade.start_fade_out(&playback_buf).await, 2);
+        assert!(crossfade.fading.load(Ordering::Acquire));
+        assert_eq!(crossfade.blend((0, 0)).await, (500, 500));
+        assert_eq!(crossfade.blend((0, 0)).await, (0, 0));
+        assert!(!crossfade.fading.load(Ordering::Acquire));
+        assert_eq!(crossfade.blend((100, 100)).await, (100, 100));
+    }
+}
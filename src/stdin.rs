@@ -24,12 +24,14 @@ pub fn init(bus: &EventBus) {
                     sources::espeak::TextToSpeechAction::Speak {
                         text: "Hello world".to_string(),
                         prio: sources::espeak::Priority::Low,
+                        voice: None,
                     },
                 )),
                 Ok(b'h') => bus.send(event::Event::TextToSpeech(
                     sources::espeak::TextToSpeechAction::Speak {
                         text: "High prio".to_string(),
                         prio: sources::espeak::Priority::High,
+                        voice: None,
                     },
                 )),
                 Ok(b'L') => {
@@ -38,6 +40,7 @@ pub fn init(bus: &EventBus) {
                         sources::espeak::TextToSpeechAction::Speak {
                             text,
                             prio: sources::espeak::Priority::High,
+                            voice: None,
                         },
                     ))
                 }
@@ -1,16 +1,44 @@
-use crate::playback::Song;
-use anyhow::{Context, Result};
+use crate::{
+    config::YoutubeConfig,
+    event::{Event, EventBus},
+    metrics,
+    playback::{Platform, PlaybackAction, Song},
+};
+use anyhow::{anyhow, Context, Result};
 use futures::TryStreamExt;
-use std::path::Path;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::{
+    io::{ErrorKind, Read, Seek, SeekFrom},
+    path::Path,
+    time::{Duration, Instant},
+};
 use symphonia::core::io::MediaSource;
 use symphonia::core::io::MediaSourceStream;
-use symphonia::core::io::ReadOnlySource;
+use tokio::sync::Semaphore;
 use tokio_util::io::StreamReader;
-use youtube_dl::{download_yt_dlp, YoutubeDl};
+use youtube_dl::{download_yt_dlp, SingleVideo, YoutubeDl};
+
+const YT_DLP_PATH: &str = "./yt-dlp";
+
+/// Oldest yt-dlp version known to work with YouTube
+const MIN_YT_DLP_VERSION: &str = "2023.12.30";
+
+/// Maximum number of concurrent yt-dlp song info lookups
+const MAX_CONCURRENT_SEARCHES: usize = 2;
+
+/// Download progress percentages at which buffering progress is reported
+const BUFFERING_PROGRESS_STEPS: [u8; 4] = [25, 50, 75, 100];
+
+lazy_static! {
+    /// Limits how many yt-dlp song info lookups may run at once, so that a
+    /// burst of requests doesn't spawn a subprocess for each of them
+    static ref SEARCH_SEMAPHORE: Semaphore = Semaphore::new(MAX_CONCURRENT_SEARCHES);
+}
 
 pub async fn init() -> anyhow::Result<()> {
     let yt_dlp_binary_exists =
-        tokio::task::spawn_blocking(|| Path::new("./yt-dlp").exists()).await?;
+        tokio::task::spawn_blocking(|| Path::new(YT_DLP_PATH).exists()).await?;
 
     if !yt_dlp_binary_exists {
         info!("Downloading yt-dlp binary");
@@ -20,14 +48,261 @@ pub async fn init() -> anyhow::Result<()> {
     Ok(())
 }
 
-pub async fn get_yt_media_source_stream(url: String) -> Result<MediaSourceStream> {
-    let output = YoutubeDl::new(url)
-        .youtube_dl_path("./yt-dlp")
+fn not_installed_error() -> anyhow::Error {
+    anyhow!("yt-dlp was not found at {YT_DLP_PATH}, restart the bot to download it")
+}
+
+/// Parses a yt-dlp version such as "2023.12.30" into comparable parts
+fn parse_version(version: &str) -> Vec<u32> {
+    version
+        .split('.')
+        .map(|part| part.parse().unwrap_or(0))
+        .collect()
+}
+
+/// Checks that yt-dlp can be run and returns its version. Warns if the
+/// version is older than [MIN_YT_DLP_VERSION] or if the configured cookies
+/// file is missing.
+pub async fn check_ytdlp_available(config: &YoutubeConfig) -> Result<String> {
+    let output = tokio::process::Command::new(YT_DLP_PATH)
+        .arg("--version")
+        .output()
+        .await
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => not_installed_error(),
+            _ => anyhow!("Failed to run yt-dlp: {e}"),
+        })?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "yt-dlp --version failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    if parse_version(&version) < parse_version(MIN_YT_DLP_VERSION) {
+        warn!(
+            "yt-dlp version {version} is older than {MIN_YT_DLP_VERSION}, upgrade it with: {YT_DLP_PATH} -U"
+        );
+    }
+
+    if let Some(cookies_file) = &config.ytdlp_cookies_file {
+        if !tokio::fs::try_exists(cookies_file).await.unwrap_or(false) {
+            warn!(
+                "yt-dlp cookies file {} does not exist",
+                cookies_file.display()
+            );
+        }
+    }
+
+    Ok(version)
+}
+
+/// Converts yt-dlp errors into something more helpful when the binary is
+/// missing or timed out after `timeout_secs`
+fn map_yt_dlp_error(e: youtube_dl::Error, timeout_secs: u64) -> anyhow::Error {
+    match e {
+        youtube_dl::Error::Io(e) if e.kind() == ErrorKind::NotFound => not_installed_error(),
+        youtube_dl::Error::ProcessTimeout => {
+            anyhow!("YouTube metadata fetch timed out after {timeout_secs}s")
+        }
+        e => e.into(),
+    }
+}
+
+/// Returns the yt-dlp arguments that select the audio format and quality
+fn audio_format_args(config: &YoutubeConfig) -> [String; 4] {
+    [
+        "--format".to_string(),
+        config.youtube_audio_format.clone(),
+        "--audio-quality".to_string(),
+        config.youtube_audio_quality.min(9).to_string(),
+    ]
+}
+
+/// Creates a [YoutubeDl] with the options shared by all yt-dlp invocations
+fn yt_dlp(url: String, config: &YoutubeConfig) -> YoutubeDl {
+    let mut yt_dlp = YoutubeDl::new(url);
+
+    for arg in audio_format_args(config) {
+        yt_dlp.extra_arg(arg);
+    }
+
+    yt_dlp
+        .youtube_dl_path(YT_DLP_PATH)
         .extract_audio(true)
-        // until symphonia has opus support
-        .format("bestaudio[ext=m4a]")
-        .run_async()
-        .await?
+        // Kills the yt-dlp process if it hangs, e.g. when YouTube blocks us
+        .process_timeout(Duration::from_secs(config.ytdlp_timeout_secs));
+
+    if let Some(cookies_file) = &config.ytdlp_cookies_file {
+        yt_dlp
+            .extra_arg("--cookies")
+            .extra_arg(cookies_file.to_string_lossy());
+    }
+
+    if let Some(browser) = &config.ytdlp_cookies_from_browser {
+        yt_dlp
+            .extra_arg("--cookies-from-browser")
+            .extra_arg(browser);
+    }
+
+    if !config.ytdlp_sponsorblock_mark.is_empty() {
+        yt_dlp
+            .extra_arg("--sponsorblock-mark")
+            .extra_arg(config.ytdlp_sponsorblock_mark.join(","));
+    }
+
+    if !config.ytdlp_sponsorblock_remove.is_empty() {
+        yt_dlp
+            .extra_arg("--sponsorblock-remove")
+            .extra_arg(config.ytdlp_sponsorblock_remove.join(","));
+    }
+
+    yt_dlp
+}
+
+/// Returns the start and end in seconds of the SponsorBlock segments of a
+/// yt-dlp video JSON that belong to a `ytdlp_sponsorblock_remove` category,
+/// sorted and with overlapping segments merged
+fn removed_segments(video: &Value, config: &YoutubeConfig) -> Vec<(f64, f64)> {
+    let Some(chapters) = video.get("sponsorblock_chapters").and_then(Value::as_array) else {
+        return vec![];
+    };
+
+    let is_removed = |category: &str| {
+        config
+            .ytdlp_sponsorblock_remove
+            .iter()
+            .any(|removed| removed == category || removed == "all")
+    };
+
+    let mut segments: Vec<(f64, f64)> = chapters
+        .iter()
+        .filter(|chapter| {
+            chapter
+                .get("category")
+                .and_then(Value::as_str)
+                .is_some_and(is_removed)
+        })
+        .filter_map(|chapter| {
+            Some((
+                chapter.get("start_time")?.as_f64()?,
+                chapter.get("end_time")?.as_f64()?,
+            ))
+        })
+        .collect();
+
+    segments.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let mut merged: Vec<(f64, f64)> = vec![];
+    for (start, end) in segments {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    merged
+}
+
+/// Non-seekable media source that reports download progress as
+/// [PlaybackAction::BufferingProgress] events while it is being read
+struct ProgressMediaSource<R: Read + Send + Sync> {
+    inner: R,
+    bus: Option<EventBus>,
+    bytes_read: u64,
+    content_length: Option<u64>,
+
+    /// Index of the next step in [BUFFERING_PROGRESS_STEPS] to report
+    next_step: usize,
+}
+
+impl<R: Read + Send + Sync> ProgressMediaSource<R> {
+    fn new(inner: R, bus: Option<EventBus>, content_length: Option<u64>) -> Self {
+        Self {
+            inner,
+            bus,
+            bytes_read: 0,
+            content_length,
+            next_step: 0,
+        }
+    }
+
+    /// Returns the progress steps that were passed since the previous call
+    fn passed_steps(&mut self) -> Vec<u8> {
+        let Some(content_length) = self.content_length.filter(|len| *len > 0) else {
+            return vec![];
+        };
+
+        let percent = (self.bytes_read * 100 / content_length).min(100) as u8;
+        let mut passed = vec![];
+
+        while let Some(step) = BUFFERING_PROGRESS_STEPS.get(self.next_step) {
+            if percent < *step {
+                break;
+            }
+
+            passed.push(*step);
+            self.next_step += 1;
+        }
+
+        passed
+    }
+}
+
+impl<R: Read + Send + Sync> Read for ProgressMediaSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n as u64;
+
+        for percent in self.passed_steps() {
+            if let Some(bus) = &self.bus {
+                bus.send(Event::Playback(PlaybackAction::BufferingProgress {
+                    percent,
+                }));
+            }
+        }
+
+        Ok(n)
+    }
+}
+
+impl<R: Read + Send + Sync> Seek for ProgressMediaSource<R> {
+    fn seek(&mut self, _pos: SeekFrom) -> std::io::Result<u64> {
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "source does not support seeking",
+        ))
+    }
+}
+
+impl<R: Read + Send + Sync> MediaSource for ProgressMediaSource<R> {
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Fetches a media stream for a YouTube URL. If `bus` is given, download
+/// progress is reported to it.
+pub async fn get_yt_media_source_stream(
+    url: String,
+    config: &YoutubeConfig,
+    bus: Option<EventBus>,
+) -> Result<MediaSourceStream> {
+    let yt_dlp = yt_dlp(url, config);
+
+    let fetch_start = Instant::now();
+    let output = yt_dlp.run_async().await;
+    metrics::YOUTUBE_FETCH_DURATION.observe_since(fetch_start);
+
+    let output = output
+        .map_err(|e| map_yt_dlp_error(e, config.ytdlp_timeout_secs))?
         .into_single_video();
 
     let video = output.context("No video found")?;
@@ -38,8 +313,9 @@ pub async fn get_yt_media_source_stream(url: String) -> Result<MediaSourceStream
     );
 
     let url = video.url.context("No URL found in yt-dlp JSON!")?;
-    let stream = reqwest::get(&url)
-        .await?
+    let response = reqwest::get(&url).await?;
+    let content_length = response.content_length();
+    let stream = response
         .bytes_stream()
         .map_err(|e| futures::io::Error::new(std::io::ErrorKind::Other, e));
 
@@ -48,31 +324,76 @@ pub async fn get_yt_media_source_stream(url: String) -> Result<MediaSourceStream
     // let reader = BufReader::new(stream.into_async_read());
     let sync_reader = tokio_util::io::SyncIoBridge::new(read);
 
-    let source = Box::new(ReadOnlySource::new(sync_reader)) as Box<dyn MediaSource>;
+    let source = Box::new(ProgressMediaSource::new(sync_reader, bus, content_length))
+        as Box<dyn MediaSource>;
 
     Ok(MediaSourceStream::new(source, Default::default()))
 }
 
-pub async fn get_yt_song_info(url_or_search_terms: String, queued_by: String) -> Result<Song> {
-    let output = YoutubeDl::new(url_or_search_terms.clone())
-        .youtube_dl_path("./yt-dlp")
-        .extract_audio(true)
-        // until symphonia has opus support
-        .format("bestaudio[ext=m4a]")
+/// Returns the lowercase tags and categories of a yt-dlp video JSON
+fn video_tags(video: &Value) -> Vec<String> {
+    let mut tags: Vec<String> = ["tags", "categories"]
+        .iter()
+        .filter_map(|key| video.get(key).and_then(Value::as_array))
+        .flatten()
+        .filter_map(Value::as_str)
+        .map(str::to_lowercase)
+        .collect();
+
+    tags.sort();
+    tags.dedup();
+
+    tags
+}
+
+/// Turns search terms into a yt-dlp query for the first YouTube search
+/// result, even if the terms look like a URL
+pub fn search_yt_query(search_terms: &str) -> String {
+    format!("ytsearch1:{search_terms}")
+}
+
+pub async fn get_yt_song_info(
+    url_or_search_terms: String,
+    queued_by: String,
+    config: &YoutubeConfig,
+) -> Result<Song> {
+    let _permit = SEARCH_SEMAPHORE.acquire().await?;
+
+    let is_url =
+        url_or_search_terms.starts_with("http://") || url_or_search_terms.starts_with("https://");
+    let timeout_secs = if is_url {
+        config.ytdlp_timeout_secs
+    } else {
+        config.search_yt_timeout_secs
+    };
+
+    // The raw JSON is needed for sponsorblock_chapters, which youtube_dl
+    // doesn't parse
+    let fetch_start = Instant::now();
+    let output = yt_dlp(url_or_search_terms, config)
         .extra_arg("--default-search")
         .extra_arg("ytsearch")
         .extra_arg("--no-playlist")
-        .run_async()
-        .await?;
+        .process_timeout(Duration::from_secs(timeout_secs))
+        .run_raw_async()
+        .await;
+    metrics::YOUTUBE_FETCH_DURATION.observe_since(fetch_start);
 
-    let single_video = output.clone().into_single_video();
-    let first_match = single_video.or_else(|| {
-        let playlist = output.into_playlist()?;
-        let entries = playlist.entries?;
-        entries.first().cloned()
-    });
+    let output = output.map_err(|e| map_yt_dlp_error(e, timeout_secs))?;
 
-    let video = first_match.context("No video found")?;
+    let first_match = match output.get("_type").and_then(Value::as_str) {
+        Some("playlist") => output
+            .get("entries")
+            .and_then(Value::as_array)
+            .and_then(|entries| entries.first()),
+        _ => Some(&output),
+    };
+
+    let video_json = first_match.context("No video found")?;
+    let skip_segments = removed_segments(video_json, config);
+    let tags = video_tags(video_json);
+    let video: SingleVideo =
+        serde_json::from_value(video_json.clone()).context("Invalid video in yt-dlp JSON")?;
     let id = video.id;
     let url = format!("https://youtu.be/{}", id);
     let title = video.title.context("No title found in yt-dlp JSON!")?;
@@ -83,6 +404,10 @@ pub async fn get_yt_song_info(url_or_search_terms: String, queued_by: String) ->
         .as_u64()
         .context("Invalid duration in yt-dlp JSON")?;
 
+    // Playback skips the removed segments, so leave them out of the duration
+    let removed_secs: f64 = skip_segments.iter().map(|(start, end)| end - start).sum();
+    let duration = duration.saturating_sub(removed_secs.round() as u64);
+
     Ok(Song {
         id,
         url,
@@ -90,5 +415,188 @@ pub async fn get_yt_song_info(url_or_search_terms: String, queued_by: String) ->
         channel,
         duration,
         queued_by,
+        enqueued_at: 0,
+        trim: None,
+        skip_segments,
+        tags,
+        platform: Platform::default(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config_removing(categories: &[&str]) -> YoutubeConfig {
+        YoutubeConfig {
+            ytdlp_sponsorblock_remove: categories.iter().map(|c| c.to_string()).collect(),
+            ..crate::config::example().youtube
+        }
+    }
+
+    fn chapter(category: &str, start_time: f64, end_time: f64) -> Value {
+        json!({
+            "category": category,
+            "start_time": start_time,
+            "end_time": end_time,
+        })
+    }
+
+    #[test]
+    fn removed_segments_are_sorted_and_merged() {
+        let video = json!({
+            "sponsorblock_chapters": [
+                chapter("sponsor", 100.0, 120.0),
+                chapter("intro", 0.0, 10.0),
+                chapter("sponsor", 110.0, 130.0),
+                chapter("music_offtopic", 20.0, 40.0),
+                chapter("selfpromo", 125.0, 140.0),
+                chapter("sponsor", 140.0, 150.0),
+            ]
+        });
+        let config = config_removing(&["sponsor", "intro", "selfpromo"]);
+
+        assert_eq!(
+            removed_segments(&video, &config),
+            [(0.0, 10.0), (100.0, 150.0)]
+        );
+    }
+
+    #[test]
+    fn removed_segments_skip_other_categories() {
+        let video = json!({
+            "sponsorblock_chapters": [
+                chapter("intro", 0.0, 10.0),
+                chapter("sponsor", 100.0, 120.0),
+            ]
+        });
+
+        assert_eq!(
+            removed_segments(&video, &config_removing(&["sponsor"])),
+            [(100.0, 120.0)]
+        );
+        assert!(removed_segments(&video, &config_removing(&[])).is_empty());
+        assert_eq!(
+            removed_segments(&video, &config_removing(&["all"])),
+            [(0.0, 10.0), (100.0, 120.0)]
+        );
+    }
+
+    #[test]
+    fn video_without_chapters_has_no_removed_segments() {
+        let config = config_removing(&["sponsor"]);
+
+        assert!(removed_segments(&json!({}), &config).is_empty());
+        assert!(removed_segments(&json!({ "sponsorblock_chapters": null }), &config).is_empty());
+    }
+
+    #[test]
+    fn video_tags_are_lowercase_and_deduplicated() {
+        let video = json!({
+            "tags": ["Sitz", "Snapsvisa", "sitz"],
+            "categories": ["Music", "snapsvisa"],
+        });
+
+        assert_eq!(video_tags(&video), ["music", "sitz", "snapsvisa"]);
+        assert!(video_tags(&json!({})).is_empty());
+    }
+
+    fn source_with_length(content_length: Option<u64>) -> ProgressMediaSource<&'static [u8]> {
+        ProgressMediaSource::new(&[], None, content_length)
+    }
+
+    #[test]
+    fn passed_steps_reports_each_step_once() {
+        let mut source = source_with_length(Some(200));
+
+        for (bytes_read, expected) in [(49, vec![]), (50, vec![25]), (60, vec![])] {
+            source.bytes_read = bytes_read;
+            assert_eq!(source.passed_steps(), expected);
+        }
+
+        for (bytes_read, expected) in [(100, 50), (150, 75), (200, 100)] {
+            source.bytes_read = bytes_read;
+            assert_eq!(source.passed_steps(), [expected]);
+        }
+
+        source.bytes_read = 300;
+        assert!(source.passed_steps().is_empty());
+    }
+
+    #[test]
+    fn passed_steps_reports_all_steps_passed_in_one_read() {
+        let mut source = source_with_length(Some(100));
+
+        source.bytes_read = 80;
+        assert_eq!(source.passed_steps(), [25, 50, 75]);
+
+        source.bytes_read = 100;
+        assert_eq!(source.passed_steps(), [100]);
+    }
+
+    #[test]
+    fn passed_steps_needs_a_content_length() {
+        for content_length in [None, Some(0)] {
+            let mut source = source_with_length(content_length);
+            source.bytes_read = 1000;
+
+            assert!(source.passed_steps().is_empty());
+        }
+    }
+
+    #[test]
+    fn reading_reports_buffering_progress() {
+        let (bus, captured) = EventBus::new_capturing();
+        let data = [0; 100];
+        let mut source = ProgressMediaSource::new(&data[..], Some(bus), Some(100));
+
+        let mut buf = [0; 60];
+        source.read_exact(&mut buf).unwrap();
+        source.read_exact(&mut buf[..40]).unwrap();
+
+        let percents: Vec<u8> = captured
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|event| match event {
+                Event::Playback(PlaybackAction::BufferingProgress { percent }) => Some(*percent),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(percents, [25, 50, 75, 100]);
+    }
+
+    #[test]
+    fn audio_format_args_pass_the_configured_format() {
+        let config = YoutubeConfig {
+            youtube_audio_format: "bestaudio[ext=mp3]".to_string(),
+            youtube_audio_quality: 12,
+            ..crate::config::example().youtube
+        };
+
+        assert_eq!(
+            audio_format_args(&config),
+            ["--format", "bestaudio[ext=mp3]", "--audio-quality", "9"]
+        );
+    }
+
+    #[test]
+    fn default_audio_format_prefers_m4a() {
+        let config: YoutubeConfig = toml::from_str("").unwrap();
+
+        assert_eq!(
+            audio_format_args(&config)[..2],
+            ["--format", "bestaudio[ext=m4a]/bestaudio"]
+        );
+    }
+
+    #[test]
+    fn search_query_searches_for_the_first_result() {
+        assert_eq!(search_yt_query("helan går"), "ytsearch1:helan går");
+        assert_eq!(
+            search_yt_query("https://example.com/song"),
+            "ytsearch1:https://example.com/song"
+        );
+    }
+}